@@ -8,12 +8,37 @@ fn main() {
     // Move to the rpi-rgb-led-matrix library
     let rpi_rgb_path_root = Path::new("rpi-rgb-led-matrix/").canonicalize().unwrap();
     let rpi_rgb_out = rpi_rgb_path_root.join("lib");
+    let shim_source = Path::new("src/c_shim.cc").canonicalize().unwrap();
     assert!(env::set_current_dir(&rpi_rgb_out).is_ok());
 
     // Make it!
     eprintln!("Making...");
     Command::new("make").args(&["HARDWARE_DESC=adafruit-hat-pwm"]).status().unwrap();
 
+    // Our own C++ shim for the bits the C API doesn't expose
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let shim_object = Path::new(&out_dir).join("c_shim.o");
+    let shim_include = rpi_rgb_path_root.join("include");
+    assert!(Command::new("g++")
+        .args(&["-c", "-O2", "-fPIC", "-I"])
+        .arg(&shim_include)
+        .arg(&shim_source)
+        .arg("-o")
+        .arg(&shim_object)
+        .status()
+        .unwrap()
+        .success());
+    assert!(Command::new("ar")
+        .arg("crs")
+        .arg(Path::new(&out_dir).join("libledmatrix_shim.a"))
+        .arg(&shim_object)
+        .status()
+        .unwrap()
+        .success());
+    println!("cargo:rerun-if-changed={}", shim_source.display());
+
+    println!("cargo:rustc-link-search=native={}", out_dir);
+    println!("cargo:rustc-link-lib=static=ledmatrix_shim");
     println!("cargo:rustc-link-search=native={}", rpi_rgb_out.as_path().display());
     println!("cargo:rustc-link-lib=static=rgbmatrix");
     println!("cargo:rustc-flags=-l dylib=stdc++");
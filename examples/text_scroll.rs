@@ -1,10 +1,10 @@
 use std::thread::sleep;
 use std::time::Duration;
-use std::path::Path;
 use rgb::*;
 
 use ledmatrix::matrix::{Matrix, LEDMatrixOptions};
-use ledmatrix::canvas::{Font, PixelLocation};
+use ledmatrix::canvas::{Font, PointF};
+use ledmatrix::font::BuiltinFont;
 use ledmatrix::matrix::HardwareMapping::AdafruitHatPWM;
 
 
@@ -27,20 +27,18 @@ fn main() {
     let mut main_canvas = matrix.get_canvas();
     let mut aux_canvas = matrix.create_offscreen_canvas();
 
-    let file_path = Path::new(file!()).parent().unwrap();
-    let font_path_buf = file_path.join(Path::new("../rpi-rgb-led-matrix/fonts/5x8.bdf"));
-    let mut font = Font::new(font_path_buf.as_path()).unwrap();
+    let font = Font::builtin(BuiltinFont::Font5x8);
 
     loop {
         for step_micro in -300..200 {
             let step: f32 = (step_micro as f32) / 100.0;
 
-            let goodbye_pos = PixelLocation::from_relative(0.25, step * 2.0 + 0.5, &main_canvas);
-            let hello_pos = PixelLocation::from_relative(step, 0.5, &main_canvas);
+            let goodbye_pos = PointF::from_relative(0.25, step * 2.0 + 0.5, &main_canvas);
+            let hello_pos = PointF::from_relative(step, 0.5, &main_canvas);
 
             main_canvas.clear();
-            main_canvas.draw_text(&mut font, &hello_pos, &rgb_hello, "Hello, World!", 0);
-            aux_canvas.vertical_draw_text(&mut font, &goodbye_pos, &rgb_goodbye, "Goodbye", 0);
+            main_canvas.draw_text(&font, &hello_pos, &rgb_hello, "Hello, World!", 0);
+            aux_canvas.vertical_draw_text(&font, &goodbye_pos, &rgb_goodbye, "Goodbye", 0);
             matrix.swap_canvas_on_vsync(&mut main_canvas, &mut aux_canvas);
             sleep(Duration::from_millis(5));
         }
@@ -0,0 +1,247 @@
+use super::canvas::Rect;
+use super::image::{DrawImageOptions, Image, Scaling};
+#[cfg(feature = "image")]
+use super::image::ImageError;
+use super::matrix::Matrix;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// GIFs commonly ask for no delay at all; like browsers, we show such
+/// frames for this long instead.
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest we sleep at once while waiting for the next frame, so that a
+/// stop request is noticed promptly.
+pub(crate) const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/*
+ * Animation
+ */
+
+/// A fully composited frame, with disposal of the previous frame already
+/// applied, and how long to show it.
+#[derive(Clone, Debug)]
+pub struct AnimationFrame {
+    pub image: Image,
+    pub delay: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Animation {
+    frames: Vec<AnimationFrame>,
+}
+
+impl Animation {
+    pub fn new(frames: Vec<AnimationFrame>) -> Animation {
+        Animation { frames }
+    }
+
+    /// Loads an animated GIF or APNG. Still images load as a single frame.
+    #[cfg(feature = "image")]
+    pub fn open(path: &std::path::Path) -> Result<Animation, ImageError> {
+        Animation::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Decodes an animated GIF or APNG held in memory.
+    #[cfg(feature = "image")]
+    pub fn from_bytes(data: &[u8]) -> Result<Animation, ImageError> {
+        use ::image::codecs::gif::GifDecoder;
+        use ::image::codecs::png::PngDecoder;
+        use ::image::{AnimationDecoder, Frames};
+        use std::io::Cursor;
+
+        let decode_error = |err: ::image::ImageError| ImageError::Decode(err.to_string());
+        let frames: Frames = match ::image::guess_format(data).map_err(decode_error)? {
+            ::image::ImageFormat::Gif => GifDecoder::new(Cursor::new(data)).map_err(decode_error)?.into_frames(),
+            ::image::ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(data)).map_err(decode_error)?;
+                if !decoder.is_apng() {
+                    return Ok(Animation::still(Image::from_bytes(data)?));
+                }
+                decoder.apng().into_frames()
+            }
+            _ => return Ok(Animation::still(Image::from_bytes(data)?)),
+        };
+
+        let mut animation = Animation::default();
+        for frame in frames {
+            let frame = frame.map_err(decode_error)?;
+            let delay = Duration::from(frame.delay());
+            animation.frames.push(AnimationFrame {
+                delay: if delay < Duration::from_millis(10) { DEFAULT_FRAME_DELAY } else { delay },
+                image: Image::from_rgba_image(frame.into_buffer()),
+            });
+        }
+        Ok(animation)
+    }
+
+    /// An animation of a single still image.
+    pub fn still(image: Image) -> Animation {
+        Animation::new(vec![AnimationFrame { image, delay: DEFAULT_FRAME_DELAY }])
+    }
+
+    pub fn frames(&self) -> &[AnimationFrame] {
+        &self.frames
+    }
+
+    /// How long one pass through all frames takes.
+    pub fn duration(&self) -> Duration {
+        self.frames.iter().map(|frame| frame.delay).sum()
+    }
+
+    /// Scales every frame onto a `width` by `height` image ahead of time, so
+    /// playback only has to copy pixels.
+    pub fn prescale(&self, width: i32, height: i32, options: &DrawImageOptions) -> Animation {
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| AnimationFrame {
+                image: frame.image.render_into(width, height, options),
+                delay: frame.delay,
+            })
+            .collect();
+        Animation { frames }
+    }
+}
+
+/*
+ * Player
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Looping {
+    Forever,
+    /// Plays the animation this many times, then returns.
+    Times(u32),
+}
+
+/// Stops a `Player`, or other playback, from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn new() -> StopHandle {
+        StopHandle { stopped: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Plays an `Animation` on a matrix, double buffered through an offscreen
+/// canvas and paced by each frame's delay.
+pub struct Player {
+    animation: Animation,
+    pub looping: Looping,
+    pub options: DrawImageOptions,
+    stop: StopHandle,
+}
+
+impl Player {
+    pub fn new(animation: Animation, looping: Looping) -> Player {
+        Player {
+            animation,
+            looping,
+            options: DrawImageOptions::new(Scaling::Fit),
+            stop: StopHandle::new(),
+        }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Plays until the loops are done or the stop handle is used. Returns
+    /// the number of frames shown.
+    pub fn play(&mut self, matrix: &mut Matrix) -> usize {
+        let mut displayed = matrix.get_canvas();
+        let mut offscreen = matrix.create_offscreen_canvas();
+        let (width, height) = offscreen.get_size();
+        let frames = self.animation.prescale(width, height, &self.options);
+        let full_canvas = Rect::new(0, 0, width, height);
+        let unscaled = DrawImageOptions::new(Scaling::None);
+
+        let mut shown = 0;
+        let mut pass = 0;
+        let mut deadline = Instant::now();
+        while !frames.frames.is_empty() && !self.stop.is_stopped() {
+            if let Looping::Times(times) = self.looping {
+                if pass >= times {
+                    break;
+                }
+            }
+
+            for frame in frames.frames() {
+                if self.stop.is_stopped() {
+                    break;
+                }
+
+                offscreen.clear();
+                offscreen.draw_image(&frame.image, &full_canvas, &unscaled);
+                matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+                // the freshly shown canvas is in `offscreen`, the free one in `displayed`
+                std::mem::swap(&mut offscreen, &mut displayed);
+                shown += 1;
+
+                // pace against the schedule rather than the last wakeup, so
+                // slow frames don't add up to drift
+                deadline += frame.delay;
+                let now = Instant::now();
+                if deadline < now {
+                    deadline = now;
+                }
+                while !self.stop.is_stopped() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    sleep((deadline - now).min(STOP_POLL_INTERVAL));
+                }
+            }
+            pass += 1;
+        }
+
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rgb::RGBA8;
+
+    #[test]
+    fn prescale_letterboxes() {
+        let red = RGBA8::new(255, 0, 0, 255);
+        let frame = AnimationFrame { image: Image::new(2, 1, vec![red; 2]), delay: Duration::from_millis(40) };
+        let animation = Animation::new(vec![frame.clone(), frame]);
+        assert_eq!(animation.duration(), Duration::from_millis(80));
+
+        let scaled = animation.prescale(4, 4, &DrawImageOptions::new(Scaling::Fit));
+        let image = &scaled.frames()[1].image;
+        assert_eq!((image.width(), image.height()), (4, 4));
+        assert_eq!(image.pixel(0, 0).a, 0);
+        assert_eq!(image.pixel(0, 1), red);
+        assert_eq!(image.pixel(3, 2), red);
+        assert_eq!(image.pixel(3, 3).a, 0);
+    }
+
+    #[test]
+    fn stop_handle_is_shared() {
+        let player = Player::new(Animation::default(), Looping::Forever);
+        let handle = player.stop_handle();
+        assert!(!player.stop_handle().is_stopped());
+        handle.stop();
+        assert!(player.stop_handle().is_stopped());
+    }
+}
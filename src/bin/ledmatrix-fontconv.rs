@@ -0,0 +1,247 @@
+// Converts TrueType fonts or PNG sprite sheets into BDF fonts for the matrix.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use ledmatrix::font::{BoundingBox, Font, Glyph, Rasterization, TtfOptions};
+
+const USAGE: &str = "Usage:
+  ledmatrix-fontconv ttf <font.ttf> <pixel-size> <out.bdf> [options]
+  ledmatrix-fontconv png <sheet.png> <cell-width>x<cell-height> <charmap.txt> <out.bdf> [options]
+
+The PNG sheet is a grid of equally sized cells read left to right, top to
+bottom, one per character of the charmap file (line breaks are ignored).
+
+Options:
+  --threshold <0-255>  TTF: coverage a pixel needs to be on (default 128)
+  --chars <string>     TTF: only convert these characters
+  --baseline <row>     PNG: row of the baseline within a cell (default: last row)
+  --invert             PNG: glyphs are dark on a light background
+  --preview <text>     render <text> with the result to the terminal";
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(1);
+}
+
+/// Pulls `--flag value` pairs and `--switch`es out of the arguments.
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        fail(&format!("{} needs a value", flag));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str, what: &str) -> T {
+    value.parse().unwrap_or_else(|_| fail(&format!("invalid {}: {}", what, value)))
+}
+
+fn convert_ttf(args: &mut Vec<String>) -> Font {
+    let threshold = take_option(args, "--threshold").map(|t| parse::<u8>(&t, "threshold"));
+    let chars = take_option(args, "--chars");
+    if args.len() != 3 {
+        fail("expected <font.ttf> <pixel-size> <out.bdf>");
+    }
+
+    let mut options = TtfOptions::new(parse(&args[1], "pixel size"));
+    options.rasterization = Rasterization::Threshold(threshold.unwrap_or(128));
+    options.chars = chars;
+    Font::from_ttf_file(Path::new(&args[0]), &options).unwrap_or_else(|err| fail(&err.to_string()))
+}
+
+/// Decodes a PNG into a grid of on/off pixels.
+fn load_sheet(path: &Path, invert: bool) -> (i32, i32, Vec<bool>) {
+    let file = File::open(path).unwrap_or_else(|err| fail(&format!("{}: {}", path.display(), err)));
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().unwrap_or_else(|err| fail(&err.to_string()));
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).unwrap_or_else(|err| fail(&err.to_string()));
+
+    let channels = frame.color_type.samples();
+    let pixels = buffer[..frame.buffer_size()]
+        .chunks(channels)
+        .map(|px| {
+            let luma = match channels {
+                1 | 2 => px[0] as u32,
+                _ => (px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000,
+            };
+            let opaque = channels % 2 == 1 || px[channels - 1] >= 128;
+            opaque && ((luma >= 128) != invert)
+        })
+        .collect();
+
+    (frame.width as i32, frame.height as i32, pixels)
+}
+
+fn convert_png(args: &mut Vec<String>) -> Font {
+    let baseline = take_option(args, "--baseline").map(|b| parse::<i32>(&b, "baseline"));
+    let invert = take_switch(args, "--invert");
+    if args.len() != 4 {
+        fail("expected <sheet.png> <cell-width>x<cell-height> <charmap.txt> <out.bdf>");
+    }
+
+    let (cell_width, cell_height) = match args[1].split_once('x') {
+        Some((w, h)) => (parse::<i32>(w, "cell width"), parse::<i32>(h, "cell height")),
+        None => fail("cell size must look like 8x12"),
+    };
+    if cell_width <= 0 || cell_height <= 0 {
+        fail("cell size must be positive");
+    }
+    let charmap = fs::read_to_string(&args[2]).unwrap_or_else(|err| fail(&format!("{}: {}", args[2], err)));
+    let (width, height, pixels) = load_sheet(Path::new(&args[0]), invert);
+    slice_sheet(width, height, &pixels, (cell_width, cell_height), baseline, &charmap).unwrap_or_else(|err| fail(&err))
+}
+
+/// Cuts a `width` by `height` sheet into one glyph per charmap character.
+fn slice_sheet(
+    width: i32,
+    height: i32,
+    pixels: &[bool],
+    (cell_width, cell_height): (i32, i32),
+    baseline: Option<i32>,
+    charmap: &str,
+) -> Result<Font, String> {
+    let columns = width / cell_width;
+    if columns == 0 {
+        return Err("sheet is narrower than one cell".to_string());
+    }
+    let baseline = baseline.unwrap_or(cell_height - 1);
+    if baseline < 0 || baseline >= cell_height {
+        return Err(format!("baseline must be between 0 and {}", cell_height - 1));
+    }
+
+    // rows below the baseline are the descent
+    let ascent = baseline + 1;
+    let descent = cell_height - ascent;
+    let mut glyphs = HashMap::new();
+
+    for (index, c) in charmap.chars().filter(|c| *c != '\n' && *c != '\r').enumerate() {
+        let (column, row) = (index as i32 % columns, index as i32 / columns);
+        if (row + 1) * cell_height > height {
+            return Err(format!("sheet has no cell for character {} ({:?})", index, c));
+        }
+
+        let mut coverage = Vec::with_capacity((cell_width * cell_height) as usize);
+        for y in 0..cell_height {
+            for x in 0..cell_width {
+                let sheet_index = ((row * cell_height + y) * width + column * cell_width + x) as usize;
+                coverage.push(if pixels[sheet_index] { 255 } else { 0 });
+            }
+        }
+
+        let bbx = BoundingBox { width: cell_width, height: cell_height, x_offset: 0, y_offset: -descent };
+        glyphs.insert(c, Glyph::new(cell_width, bbx, coverage)?);
+    }
+
+    Ok(Font::from_glyphs("", ascent, descent, glyphs))
+}
+
+/// Prints `text` as drawn by `font`, one terminal cell per LED.
+fn preview(font: &Font, text: &str) {
+    let width = font.measure(text, 0).width.max(0);
+    let mut rows = vec![vec![' '; width as usize]; font.height() as usize];
+
+    let mut origin = 0;
+    for c in text.chars() {
+        if let Some(glyph) = font.glyph(c) {
+            let left = origin + glyph.bbx.x_offset;
+            let top = font.baseline() - glyph.bbx.height - glyph.bbx.y_offset;
+            for y in 0..glyph.bbx.height {
+                for x in 0..glyph.bbx.width {
+                    let (px, py) = (left + x, top + y);
+                    if glyph.coverage(x, y) >= 128 && px >= 0 && px < width && py >= 0 && py < font.height() {
+                        rows[py as usize][px as usize] = '█';
+                    }
+                }
+            }
+        }
+        origin += font.char_width(c);
+    }
+
+    for row in rows {
+        println!("|{}|", row.into_iter().collect::<String>());
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || take_switch(&mut args, "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let preview_text = take_option(&mut args, "--preview");
+    if args.is_empty() {
+        fail("expected an input type");
+    }
+    let mode = args.remove(0);
+    let font = match mode.as_str() {
+        "ttf" => convert_ttf(&mut args),
+        "png" => convert_png(&mut args),
+        _ => fail(&format!("unknown input type {}", mode)),
+    };
+
+    let out_path = args.last().unwrap();
+    let file = File::create(out_path).unwrap_or_else(|err| fail(&format!("{}: {}", out_path, err)));
+    let mut out = BufWriter::new(file);
+    font.write_bdf(&mut out)
+        .and_then(|_| out.flush())
+        .unwrap_or_else(|err| fail(&err.to_string()));
+    eprintln!("wrote {} glyphs to {}", font.glyph_count(), out_path);
+
+    if let Some(text) = preview_text {
+        preview(&font, &text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_sheet() {
+        // two rows of two 2x3 cells: "a" is a line down the left, "b" is
+        // blank and "c" is only its top left pixel
+        let (o, x) = (false, true);
+        #[rustfmt::skip]
+        let pixels = [
+            x, o, o, o,
+            x, o, o, o,
+            x, o, o, o,
+            x, o, o, o,
+            o, o, o, o,
+            o, o, o, o,
+        ];
+        let font = slice_sheet(4, 6, &pixels, (2, 3), Some(1), "a\nbc").unwrap();
+        assert_eq!((font.baseline(), font.height(), font.glyph_count()), (2, 3, 3));
+
+        let a = font.glyph('a').unwrap();
+        assert_eq!((a.advance, a.bbx.y_offset), (2, -1));
+        assert_eq!((0..3).map(|y| a.coverage(0, y)).collect::<Vec<_>>(), vec![255, 255, 255]);
+        assert_eq!(a.coverage(1, 0), 0);
+        assert!((0..3).all(|y| font.glyph('b').unwrap().coverage(0, y) == 0));
+        assert_eq!(font.glyph('c').unwrap().coverage(0, 0), 255);
+
+        assert!(slice_sheet(1, 6, &pixels[..6], (2, 3), None, "a").is_err());
+        assert!(slice_sheet(4, 6, &pixels, (2, 3), Some(3), "a").is_err());
+        assert!(slice_sheet(4, 6, &pixels, (2, 3), None, "abcde").is_err());
+    }
+}
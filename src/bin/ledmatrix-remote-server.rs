@@ -0,0 +1,42 @@
+// Shows frames sent by `Matrix::connect` clients on the panels attached here.
+
+use std::env;
+use std::process;
+
+use ledmatrix::animation::StopHandle;
+use ledmatrix::matrix::{HardwareMapping, LEDMatrixOptions, Matrix};
+use ledmatrix::net::remote::{Server, DEFAULT_PORT};
+
+const USAGE: &str = "Usage:
+  ledmatrix-remote-server [--listen <address>] [--led-... options]
+
+Listens on 0.0.0.0:7892 unless told otherwise. The panel layout is set with
+the C library's usual --led-rows, --led-cols, --led-chain and friends.";
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let listen = match args.iter().position(|arg| arg == "--listen") {
+        Some(index) => args.get(index + 1).cloned().unwrap_or_else(|| fail("--listen needs a value")),
+        None => format!("0.0.0.0:{}", DEFAULT_PORT),
+    };
+
+    // the C library picks up the --led-... flags itself
+    let options = LEDMatrixOptions::new(HardwareMapping::Regular, 32, 32, 1, 1, 100);
+    let mut matrix = Matrix::new_from_options(&options);
+    let (width, height) = matrix.create_offscreen_canvas().get_size();
+
+    let mut server = Server::bind(&listen, width, height).unwrap_or_else(|err| fail(&format!("{}: {}", listen, err)));
+    eprintln!("showing {}x{} frames from {}", width, height, listen);
+    if let Err(err) = server.run(&mut matrix, &StopHandle::new()) {
+        fail(&err.to_string());
+    }
+}
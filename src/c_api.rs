@@ -1,6 +1,6 @@
 extern crate libc;
 
-use libc::{c_char, c_int};
+use libc::{c_char, c_int, size_t};
 use super::c_datatypes::*;
 
 
@@ -34,45 +34,11 @@ extern "C" {
     pub(crate) fn led_canvas_set_pixel(canvas: *mut LedCanvas, x: c_int, y: c_int, r: u8, g: u8, b: u8);
     pub(crate) fn led_canvas_clear(canvas: *mut LedCanvas);
     pub(crate) fn led_canvas_fill(canvas: *mut LedCanvas, r: u8, g: u8, b: u8);
+}
 
-    pub(crate) fn draw_text(
-        c: *mut LedCanvas,
-        font: *mut LedFont,
-        x: c_int,
-        y: c_int,
-        r: u8,
-        g: u8,
-        b: u8,
-        utf8_text: *const c_char,
-        kerning_offset: c_int,
-    );
-
-    pub(crate) fn vertical_draw_text(
-        c: *mut LedCanvas,
-        font: *mut LedFont,
-        x: c_int,
-        y: c_int,
-        r: u8,
-        g: u8,
-        b: u8,
-        utf8_text: *const c_char,
-        kerning_offset: c_int,
-    );
-
-    pub(crate) fn draw_circle(c: *mut LedCanvas, xx: c_int, y: c_int, radius: c_int, r: u8, g: u8, b: u8);
-    pub(crate) fn draw_line(
-        c: *mut LedCanvas,
-        x0: c_int,
-        y0: c_int,
-        x1: c_int,
-        y1: c_int,
-        r: u8,
-        g: u8,
-        b: u8,
-    );
-
-    // --- other --- //
-
-    pub(crate) fn load_font(bdf_font_file: *const c_char) -> *mut LedFont;
-    pub(crate) fn delete_font(font: *mut LedFont);
+// Implemented by our own C++ shim, see c_shim.cc
+#[link(name = "ledmatrix_shim")]
+extern "C" {
+    pub(crate) fn rs_led_canvas_serialize(canvas: *const LedCanvas, data: *mut *const c_char, len: *mut size_t);
+    pub(crate) fn rs_led_canvas_deserialize(canvas: *mut LedCanvas, data: *const c_char, len: size_t) -> c_int;
 }
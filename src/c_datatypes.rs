@@ -18,11 +18,6 @@ pub(crate) struct LedCanvas {
     _private: [u8; 0],
 }
 
-#[repr(C)]
-pub(crate) struct LedFont {
-    _private: [u8; 0],
-}
-
 /**
  * Parameters to create a new matrix.
  *
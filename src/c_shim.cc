@@ -0,0 +1,22 @@
+// Bindings for parts of the C++ API that the C API doesn't expose.
+//
+// A `struct LedCanvas *` handed out by the C API is a `FrameCanvas *` in
+// disguise, so we can reach its methods by casting it back.
+
+#include <stddef.h>
+
+#include "led-matrix.h"
+
+using rgb_matrix::FrameCanvas;
+
+extern "C" {
+
+void rs_led_canvas_serialize(const struct LedCanvas *canvas, const char **data, size_t *len) {
+  reinterpret_cast<const FrameCanvas *>(canvas)->Serialize(data, len);
+}
+
+int rs_led_canvas_deserialize(struct LedCanvas *canvas, const char *data, size_t len) {
+  return reinterpret_cast<FrameCanvas *>(canvas)->Deserialize(data, len) ? 1 : 0;
+}
+
+}  // extern "C"
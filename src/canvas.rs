@@ -1,129 +1,423 @@
 use super::c_api;
 use super::c_datatypes;
 
-use std::path::Path;
-use libc::c_int;
-use std::ffi::CString;
+pub use super::font::Font;
+use super::image::{DrawImageOptions, Image};
+use super::screenshot::{self, ScreenshotOptions};
+use super::text::{RichText, TextBox, TextStyle};
+
+use libc::{c_char, c_int, size_t};
 
 use rgb::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Add, Mul, Sub};
+use std::path::Path;
+use std::time::Duration;
 
 
 /*
  * Canvas
  */
 
+/// A frame buffer of the matrix. Besides the C library's buffer, it keeps a
+/// shadow copy of every pixel drawn, since the C library can't read its
+/// pixels back. Canvases of a remote matrix have only the shadow.
 pub struct Canvas {
     pub(crate) canvas: *mut c_datatypes::LedCanvas,
+    width: i32,
+    height: i32,
+    pub(crate) pixels: Vec<RGB8>,
 }
 
 impl Canvas {
     pub(crate) fn new(canvas_ref: *mut c_datatypes::LedCanvas) -> Canvas {
-        Canvas { canvas: canvas_ref }
-    }
-
-    /// Gets the total size of the canvas, taking into account the number
-    /// of parallel and series panels you have.
-    pub fn get_size(&self) -> (i32, i32) {
         let mut width: c_int = 0;
         let mut height: c_int = 0;
 
         unsafe {
-            c_api::led_canvas_get_size(self.canvas, &mut width as *mut c_int, &mut height as *mut c_int);
+            c_api::led_canvas_get_size(canvas_ref, &mut width as *mut c_int, &mut height as *mut c_int);
         }
 
-        return (width as i32, height as i32);
+        Canvas {
+            canvas: canvas_ref,
+            width: width as i32,
+            height: height as i32,
+            pixels: vec![RGB8::default(); (width * height).max(0) as usize],
+        }
+    }
+
+    /// A canvas whose shadow starts out as `pixels`, for buffers whose
+    /// contents we already know.
+    pub(crate) fn with_pixels(canvas_ref: *mut c_datatypes::LedCanvas, pixels: &[RGB8]) -> Canvas {
+        let mut canvas = Canvas::new(canvas_ref);
+        if pixels.len() == canvas.pixels.len() {
+            canvas.pixels.copy_from_slice(pixels);
+        }
+        canvas
+    }
+
+    /// A canvas without a C library buffer, for matrices that aren't
+    /// attached to this machine.
+    pub(crate) fn detached(width: i32, height: i32, pixels: &[RGB8]) -> Canvas {
+        let mut canvas = Canvas {
+            canvas: std::ptr::null_mut(),
+            width,
+            height,
+            pixels: vec![RGB8::default(); (width * height).max(0) as usize],
+        };
+        if pixels.len() == canvas.pixels.len() {
+            canvas.pixels.copy_from_slice(pixels);
+        }
+        canvas
+    }
+
+    pub(crate) fn is_detached(&self) -> bool {
+        self.canvas.is_null()
+    }
+
+    /// Gets the total size of the canvas, taking into account the number
+    /// of parallel and series panels you have.
+    pub fn get_size(&self) -> (i32, i32) {
+        (self.width, self.height)
     }
 
     pub fn clear(&mut self) {
-        unsafe {
-            c_api::led_canvas_clear(self.canvas);
+        if !self.is_detached() {
+            unsafe {
+                c_api::led_canvas_clear(self.canvas);
+            }
         }
+        self.pixels.iter_mut().for_each(|pixel| *pixel = RGB8::default());
     }
 
     pub fn fill(&mut self, rgb: &RGB8) {
+        if !self.is_detached() {
+            unsafe {
+                c_api::led_canvas_fill(self.canvas, rgb.r, rgb.g, rgb.b);
+            }
+        }
+        self.pixels.iter_mut().for_each(|pixel| *pixel = *rgb);
+    }
+
+    /// The color last drawn at `pixel`, black outside the canvas. This is
+    /// the color asked for, before the matrix applies brightness and gamma.
+    pub fn pixel(&self, pixel: &PixelLocation) -> RGB8 {
+        match self.index_of(pixel) {
+            Some(index) => self.pixels[index],
+            None => RGB8::default(),
+        }
+    }
+
+    /// Every pixel of the canvas, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.pixels
+    }
+
+    /// Renders the canvas into an image, upscaled as `options` says.
+    pub fn screenshot(&self, options: &ScreenshotOptions) -> Image {
+        screenshot::render(&self.pixels, self.width, self.height, options)
+    }
+
+    /// Saves a screenshot as a binary PPM file.
+    pub fn save_ppm(&self, path: &Path, options: &ScreenshotOptions) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        screenshot::write_ppm(&self.screenshot(options), &mut out)?;
+        out.flush()
+    }
+
+    /// Saves a screenshot as a PNG file.
+    #[cfg(feature = "png")]
+    pub fn save_png(&self, path: &Path, options: &ScreenshotOptions) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        screenshot::write_png(&self.screenshot(options), &mut out)?;
+        out.flush()
+    }
+
+    fn index_of(&self, pixel: &PixelLocation) -> Option<usize> {
+        if pixel.x < 0 || pixel.y < 0 || pixel.x >= self.width || pixel.y >= self.height {
+            return None;
+        }
+        Some((pixel.y * self.width + pixel.x) as usize)
+    }
+
+    /// The canvas in the C library's internal PWM bitplane format, as
+    /// written to `.stream` files. Only meaningful to a matrix with the
+    /// exact same configuration. Empty for a remote matrix's canvas.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data: *const c_char = std::ptr::null();
+        let mut len: size_t = 0;
+        if self.is_detached() {
+            return Vec::new();
+        }
+
         unsafe {
-            c_api::led_canvas_fill(self.canvas, rgb.r, rgb.g, rgb.b);
+            c_api::rs_led_canvas_serialize(self.canvas, &mut data, &mut len);
+            if data.is_null() {
+                return Vec::new();
+            }
+            // the buffer belongs to the canvas, so copy it out
+            std::slice::from_raw_parts(data as *const u8, len).to_vec()
         }
     }
 
+    /// Restores contents produced by `serialize`. Returns false, leaving the
+    /// canvas untouched, if `data` was made for a different configuration.
+    /// The bitplanes can't be decoded, so `pixels` doesn't follow along.
+    pub fn deserialize(&mut self, data: &[u8]) -> bool {
+        if self.is_detached() {
+            return false;
+        }
+        unsafe { c_api::rs_led_canvas_deserialize(self.canvas, data.as_ptr() as *const c_char, data.len()) != 0 }
+    }
+
     pub fn set_pixel(&mut self, pixel: &PixelLocation, rgb: &RGB8) {
-        unsafe {
-            c_api::led_canvas_set_pixel(self.canvas, pixel.x, pixel.y, rgb.r, rgb.g, rgb.b);
+        if let Some(index) = self.index_of(pixel) {
+            self.pixels[index] = *rgb;
+            if !self.is_detached() {
+                unsafe {
+                    c_api::led_canvas_set_pixel(self.canvas, pixel.x, pixel.y, rgb.r, rgb.g, rgb.b);
+                }
+            }
         }
     }
 
+    pub fn fill_rect(&mut self, rect: &Rect, rgb: &RGB8) {
+        for y in rect.y..(rect.y + rect.height) {
+            for x in rect.x..(rect.x + rect.width) {
+                self.set_pixel(&PixelLocation { x, y }, rgb);
+            }
+        }
+    }
+
+    /// Copies a block of `at.width` by `at.height` pixels, row by row, into
+    /// the `at` rectangle. Pixels falling outside the canvas are skipped.
+    pub fn set_pixels(&mut self, at: &Rect, pixels: &[RGB8]) {
+        if at.width <= 0 {
+            return;
+        }
+        for (row, colors) in pixels.chunks(at.width as usize).take(at.height.max(0) as usize).enumerate() {
+            for (column, rgb) in colors.iter().enumerate() {
+                self.set_pixel(&PixelLocation { x: at.x + column as i32, y: at.y + row as i32 }, rgb);
+            }
+        }
+    }
+
+    /// Draws `image` into the `at` rectangle, scaled as `options` says.
+    /// Nothing is drawn outside of `at`.
+    pub fn draw_image(&mut self, image: &Image, at: &Rect, options: &DrawImageOptions) {
+        let placement = image.placement(at, options.scaling);
+        let visible = match placement.intersection(at) {
+            Some(visible) => visible,
+            None => return,
+        };
+        // only resample what shows, a filled strip can be huge
+        let window = Rect::new(visible.x - placement.x, visible.y - placement.y, visible.width, visible.height);
+        let scaled = image.resize_window(placement.width, placement.height, &window, options.resampling);
+
+        for y in 0..visible.height {
+            for x in 0..visible.width {
+                if let Some(rgb) = options.transparency.apply(scaled.pixel(x, y)) {
+                    self.set_pixel(&PixelLocation { x: visible.x + x, y: visible.y + y }, &rgb);
+                }
+            }
+        }
+    }
+
+    /// Draws the outline of a circle, pixel for pixel the same as the C
+    /// library's `DrawCircle`.
     pub fn draw_circle(&mut self, pixel: &PixelLocation, radius: i32, rgb: &RGB8) {
-        unsafe {
-            c_api::draw_circle(self.canvas, pixel.x, pixel.y, radius, rgb.r, rgb.g, rgb.b);
+        let mut x = radius;
+        let mut y = 0;
+        let mut radius_error = 1 - x;
+        while y <= x {
+            for (dx, dy) in [(x, y), (y, x), (-x, y), (-y, x), (-x, -y), (-y, -x), (x, -y), (y, -x)] {
+                self.set_pixel(&PixelLocation { x: pixel.x + dx, y: pixel.y + dy }, rgb);
+            }
+            y += 1;
+            if radius_error < 0 {
+                radius_error += 2 * y + 1;
+            } else {
+                x -= 1;
+                radius_error += 2 * (y - x + 1);
+            }
         }
     }
 
+    /// Draws a line, pixel for pixel the same as the C library's `DrawLine`.
     pub fn draw_line(&mut self, p0: &PixelLocation, p1: &PixelLocation, rgb: &RGB8) {
-        unsafe {
-            c_api::draw_line(self.canvas, p0.x, p0.y, p1.x, p1.y, rgb.r, rgb.g, rgb.b);
+        // 16.16 fixed point, starting half a pixel in so positions round
+        const SHIFT: i32 = 16;
+        const HALF: i32 = 1 << (SHIFT - 1);
+
+        let (mut p0, mut p1) = (*p0, *p1);
+        let (dx, dy) = (p1.x - p0.x, p1.y - p0.y);
+        if dx.abs() > dy.abs() {
+            if p1.x < p0.x {
+                std::mem::swap(&mut p0, &mut p1);
+            }
+            let gradient = (((p1.y - p0.y) as i64) << SHIFT) / (p1.x - p0.x) as i64;
+            let mut y = (HALF as i64) + ((p0.y as i64) << SHIFT);
+            for x in p0.x..=p1.x {
+                self.set_pixel(&PixelLocation { x, y: (y >> SHIFT) as i32 }, rgb);
+                y += gradient;
+            }
+        } else if dy != 0 {
+            if p1.y < p0.y {
+                std::mem::swap(&mut p0, &mut p1);
+            }
+            let gradient = (((p1.x - p0.x) as i64) << SHIFT) / (p1.y - p0.y) as i64;
+            let mut x = (HALF as i64) + ((p0.x as i64) << SHIFT);
+            for y in p0.y..=p1.y {
+                self.set_pixel(&PixelLocation { x: (x >> SHIFT) as i32, y }, rgb);
+                x += gradient;
+            }
+        } else {
+            self.set_pixel(&p0, rgb);
         }
     }
 
-    pub fn draw_text(
+    /// Draws `utf8_text` with its baseline starting at `pixel_start` and
+    /// returns how far x advanced, the same as `Font::measure` reports.
+    pub fn draw_text<P: Into<PointF> + Copy>(
         &mut self,
-        font: &mut Font,
-        pixel_start: &PixelLocation,
+        font: &Font,
+        pixel_start: &P,
         rgb: &RGB8,
         utf8_text: &str,
         kerning_offset: i32,
-    ) {
-        let cstr = CString::new(utf8_text).unwrap().into_raw();
-        unsafe {
-            c_api::draw_text(
-                self.canvas,
-                font.font,
-                pixel_start.x,
-                pixel_start.y,
-                rgb.r,
-                rgb.g,
-                rgb.b,
-                cstr,
-                kerning_offset
-            );
-            let _ = CString::from_raw(cstr);  // free the raw string
-        }
-    }
-
-    pub fn vertical_draw_text(
+    ) -> i32 {
+        self.draw_styled_text(font, pixel_start, &TextStyle::new(*rgb), utf8_text, kerning_offset)
+    }
+
+    /// Draws `utf8_text` top to bottom, one character per line, and returns
+    /// how far y advanced.
+    pub fn vertical_draw_text<P: Into<PointF> + Copy>(
         &mut self,
-        font: &mut Font,
-        pixel_start: &PixelLocation,
+        font: &Font,
+        pixel_start: &P,
         rgb: &RGB8,
         utf8_text: &str,
         kerning_offset: i32,
-    ) {
-        let cstr = CString::new(utf8_text).unwrap().into_raw();
-        unsafe {
-            c_api::vertical_draw_text(
-                self.canvas,
-                font.font,
-                pixel_start.x,
-                pixel_start.y,
-                rgb.r,
-                rgb.g,
-                rgb.b,
-                cstr,
-                kerning_offset
-            );
-            let _ = CString::from_raw(cstr);  // free the raw string
+    ) -> i32 {
+        self.vertical_draw_styled_text(font, pixel_start, &TextStyle::new(*rgb), utf8_text, kerning_offset)
+    }
+
+    /// `draw_text` with a background, outline and/or drop shadow.
+    pub fn draw_styled_text<P: Into<PointF> + Copy>(
+        &mut self,
+        font: &Font,
+        pixel_start: &P,
+        style: &TextStyle,
+        utf8_text: &str,
+        kerning_offset: i32,
+    ) -> i32 {
+        let start = (*pixel_start).into().to_pixel(Rounding::Nearest);
+        let advance = font.measure(utf8_text, kerning_offset).width;
+
+        if let Some(background) = style.background {
+            let cell = Rect::new(start.x, start.y - font.baseline(), advance, font.height());
+            self.fill_rect(&cell, &background);
+        }
+
+        for (offset, color) in style.layers() {
+            let mut x = start.x + offset.x;
+            for c in utf8_text.chars() {
+                x += self.draw_glyph(font, x, start.y + offset.y, &color, c) + kerning_offset;
+            }
+        }
+
+        advance
+    }
+
+    /// `vertical_draw_text` with a background, outline and/or drop shadow.
+    pub fn vertical_draw_styled_text<P: Into<PointF> + Copy>(
+        &mut self,
+        font: &Font,
+        pixel_start: &P,
+        style: &TextStyle,
+        utf8_text: &str,
+        kerning_offset: i32,
+    ) -> i32 {
+        let start = (*pixel_start).into().to_pixel(Rounding::Nearest);
+        let step = font.height() + kerning_offset;
+
+        if let Some(background) = style.background {
+            let mut y = start.y;
+            for c in utf8_text.chars() {
+                let cell = Rect::new(start.x, y - font.baseline(), font.char_width(c), font.height());
+                self.fill_rect(&cell, &background);
+                y += step;
+            }
+        }
+
+        for (offset, color) in style.layers() {
+            let mut y = start.y + offset.y;
+            for c in utf8_text.chars() {
+                self.draw_glyph(font, start.x + offset.x, y, &color, c);
+                y += step;
+            }
         }
+
+        step * utf8_text.chars().count() as i32
     }
 
-    pub fn draw_line_antialiased(&mut self, pp0: &PixelLocation, pp1: &PixelLocation, rgb: &RGB8) {
-        // integer part of x
-        fn ipart(x: f32) -> f32 {
-            x.floor()
+    /// Draws every span of `rich_text` along one baseline and returns how far
+    /// x advanced. `elapsed` is the animation time used to blink spans.
+    pub fn draw_rich_text<P: Into<PointF> + Copy>(
+        &mut self,
+        rich_text: &RichText,
+        pixel_start: &P,
+        elapsed: Duration,
+    ) -> i32 {
+        let start = (*pixel_start).into().to_pixel(Rounding::Nearest);
+        let mut x = start.x;
+        for span in &rich_text.spans {
+            let font = rich_text.font_of(span);
+            if span.is_visible(elapsed) {
+                self.draw_styled_text(font, &PixelLocation { x, y: start.y }, &span.style, &span.text, rich_text.kerning_offset);
+            }
+            x += font.measure(&span.text, rich_text.kerning_offset).width;
         }
+        x - start.x
+    }
+
+    /// Lays `utf8_text` out with `text_box` and draws it.
+    pub fn draw_text_box(&mut self, text_box: &TextBox, style: &TextStyle, utf8_text: &str) {
+        for run in text_box.layout(utf8_text) {
+            self.draw_styled_text(text_box.font, &run.origin, style, &run.text, text_box.kerning_offset);
+        }
+    }
 
-        fn round(x: f32) -> f32 {
-            ipart(x + 0.5)
+    /// Draws a single glyph with its origin at (`x`, `baseline`) and returns
+    /// the advance to the next glyph. Missing glyphs draw nothing.
+    fn draw_glyph(&mut self, font: &Font, x: i32, baseline: i32, rgb: &RGB8, c: char) -> i32 {
+        let glyph = match font.glyph_or_replacement(c) {
+            Some(glyph) => glyph,
+            None => return 0,
+        };
+
+        let left = x + glyph.bbx.x_offset;
+        let top = baseline - glyph.bbx.height - glyph.bbx.y_offset;
+        for gy in 0..glyph.bbx.height {
+            for gx in 0..glyph.bbx.width {
+                match glyph.coverage(gx, gy) {
+                    0 => {}
+                    255 => self.set_pixel(&PixelLocation { x: left + gx, y: top + gy }, rgb),
+                    coverage => {
+                        let scaled = rgb.map(|px| (px as u16 * coverage as u16 / 255) as u8);
+                        self.set_pixel(&PixelLocation { x: left + gx, y: top + gy }, &scaled);
+                    }
+                }
+            }
         }
 
+        glyph.advance
+    }
+
+    /// Draws an antialiased line using Xiaolin Wu's algorithm. The endpoints
+    /// may be given either as whole pixels or as sub-pixel `PointF`s.
+    pub fn draw_line_antialiased<P: Into<PointF> + Copy>(&mut self, pp0: &P, pp1: &P, rgb: &RGB8) {
         // fractional part of x
         fn fpart(x: f32) -> f32 {
             x - x.floor()
@@ -133,189 +427,266 @@ impl Canvas {
             1.0 - fpart(x)
         }
 
-        let mut p0: PixelLocation = pp0.clone();
-        let mut p1: PixelLocation = pp1.clone();
-        let mut pswap = PixelLocation{x: 0, y: 0};
-
-        let steep = (p1.y as f32 - p0.y as f32).abs() > (p1.x as f32 - p0.x as f32).abs();
+        let mut p0: PointF = (*pp0).into();
+        let mut p1: PointF = (*pp1).into();
 
+        let steep = (p1.y - p0.y).abs() > (p1.x - p0.x).abs();
         if steep {
-            // swap x & y on p0
-            pswap.x = p0.x;
-            p0.x = p0.y;
-            p0.y = pswap.x;
-
-            // swap x & y on p1
-            pswap.x = p1.x;
-            p1.x = p1.y;
-            p1.y = pswap.x;
+            std::mem::swap(&mut p0.x, &mut p0.y);
+            std::mem::swap(&mut p1.x, &mut p1.y);
         }
-
         if p0.x > p1.x {
-            // swap x cords for p0 & p1
-            pswap.x = p0.x;
-            p0.x = p1.x;
-            p0.x = pswap.x;
-
-            // swap y cords for p0 & p1
-            pswap.y = p0.y;
-            p0.y = p1.y;
-            p0.y = pswap.y;
+            std::mem::swap(&mut p0, &mut p1);
         }
 
-        let dx = (p1.x - p0.x) as f32;
-        let dy = (p1.y - p0.y) as f32;
-        let mut gradient = dy / dx;
-        if dx == 0.0 {
-            gradient = 1.0;
-        }
+        let dx = p1.x - p0.x;
+        let dy = p1.y - p0.y;
+        let gradient = if dx == 0.0 { 1.0 } else { dy / dx };
+
+        // plots in the (possibly transposed) coordinate space of the algorithm
+        let mut plot = |x: i32, y: i32, intensity: f32| {
+            let pixel = if steep { PixelLocation { x: y, y: x } } else { PixelLocation { x, y } };
+            self.set_pixel(&pixel, &rgb.map(|px| (px as f32 * intensity) as u8));
+        };
 
         // handle first endpoint
-        let xend = round(p0.x as f32);
-        let yend = p0.y as f32 + gradient * (xend - p0.x as f32);
-        let xgap = rfpart(p0.x as f32 + 0.5);
-        let xpxl1 = xend as i32; // this will be used in the main loop;
+        let xend = p0.x.round();
+        let yend = p0.y + gradient * (xend - p0.x);
+        let xgap = rfpart(p0.x + 0.5);
+        let xpxl1 = xend as i32; // this will be used in the main loop
         let ypxl1 = yend.floor() as i32;
-        if steep {
-            let pixel = PixelLocation{x: ypxl1, y: xpxl1};
-            print!("{} -> ", pixel);
-            let _rgb = rgb.map(|px| (px as f32 * rfpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-
-            let pixel = PixelLocation{x: ypxl1 + 1, y: xpxl1};
-            let _rgb = rgb.map(|px| (px as f32 * fpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-        } else {
-            let pixel = PixelLocation{x: xpxl1, y: ypxl1};
-            print!("{} -> ", pixel);
-            let _rgb = rgb.map(|px| (px as f32 * rfpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-
-            let pixel = PixelLocation{x: xpxl1, y: ypxl1 + 1};
-            let _rgb = rgb.map(|px| (px as f32 * fpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-        }
-        let mut intery = yend + gradient; // first y-intersection for the main loop;
+        plot(xpxl1, ypxl1, rfpart(yend) * xgap);
+        plot(xpxl1, ypxl1 + 1, fpart(yend) * xgap);
+        let mut intery = yend + gradient; // first y-intersection for the main loop
 
         // handle second endpoint
-        let xend = p1.x as f32;
-        let yend = p1.y as f32 + gradient * (xend - p1.x as f32);
-        let xgap = fpart(p1.x as f32 + 0.5);
-        let xpxl2 = xend as i32;         // this will be used in the main loop;
+        let xend = p1.x.round();
+        let yend = p1.y + gradient * (xend - p1.x);
+        let xgap = fpart(p1.x + 0.5);
+        let xpxl2 = xend as i32; // this will be used in the main loop
         let ypxl2 = yend.floor() as i32;
-        if steep {
-            let pixel = PixelLocation{x: ypxl2, y: xpxl2};
-            println!("{}", pixel);
-            let _rgb = rgb.map(|px| (px as f32 * rfpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-
-            let pixel = PixelLocation{x: ypxl2 + 1, y: xpxl2};
-            let _rgb = rgb.map(|px| (px as f32 * fpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-        } else {
-            let pixel = PixelLocation{x: xpxl2, y: ypxl2};
-            println!("{}", pixel);
-            let _rgb = rgb.map(|px| (px as f32 * rfpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-
-            let pixel = PixelLocation{x: xpxl2, y: ypxl2 + 1};
-            let _rgb = rgb.map(|px| (px as f32 * fpart(yend) * xgap) as u8);
-            self.set_pixel(&pixel, &_rgb);
-        }
+        plot(xpxl2, ypxl2, rfpart(yend) * xgap);
+        plot(xpxl2, ypxl2 + 1, fpart(yend) * xgap);
 
         // main loop
-        let mut pix = PixelLocation{ x: 0, y: 0 };
-        if steep {
-            for x in (xpxl1 + 1)..(xpxl2 - 1) {
-                pix.y = x;
-                pix.x = intery.floor() as i32;
-                println!("\t{}", pix);
-                self.set_pixel(&pix, &rgb.map(|pix| ((pix as f32) * rfpart(intery)) as u8));
-
-                pix.x = (intery.floor() as i32) + 1;
-                self.set_pixel(&pix, &rgb.map(|pix| ((pix as f32) * fpart(intery)) as u8));
-                intery = intery + gradient;
-            }
-        } else {
-            for x in (xpxl1 + 1)..(xpxl2 - 1) {
-                pix.x = x;
-                pix.y = intery.floor() as i32;
-                println!("\t{}", pix);
-                self.set_pixel(&pix, &rgb.map(|pix| ((pix as f32) * rfpart(intery)) as u8));
-
-                pix.y = (intery.floor() as i32) + 1;
-                self.set_pixel(&pix, &rgb.map(|pix| ((pix as f32) * fpart(intery)) as u8));
-                intery = intery + gradient;
-            }
+        for x in (xpxl1 + 1)..xpxl2 {
+            plot(x, intery.floor() as i32, rfpart(intery));
+            plot(x, intery.floor() as i32 + 1, fpart(intery));
+            intery += gradient;
         }
     }
 }
 
 /*
- * Font
+ * Pixel Location
  */
 
-pub struct Font {
-    font: *mut c_datatypes::LedFont,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PixelLocation {
+    pub x: i32,
+    pub y: i32,
 }
 
-impl Font {
-    pub fn new(bdf_filepath: &Path) -> Result<Font, &'static str> {
-        // validate path
-        let abs_path = bdf_filepath.canonicalize().unwrap();
-        if !abs_path.exists() {
-            return Err("Filepath does not appear to exist!");
-        }
+impl PixelLocation {
+    pub fn from_relative(x: f32, y: f32, canvas: &Canvas) -> PixelLocation {
+        let (xsize, ysize) = canvas.get_size();
 
-        if let Some(ext) = abs_path.extension() {
-            if !(ext == "bdf") {
-                return Err("Given filepath does not appear to be a .bdf file!");
-            }
-        } else {
-            return Err("Given filepath doesn't even have a file extension!");
+        PixelLocation {
+            x: (xsize as f32 * x) as i32,
+            y: (ysize as f32 * y) as i32,
         }
+    }
+}
 
-        // make the object
-        let string = CString::new(abs_path.to_str().unwrap()).unwrap();
-        unsafe {
-            return Ok(Font {
-                font: c_api::load_font(string.as_ptr()),
-            });
-        }
+impl std::fmt::Display for PixelLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({:<3}, {:<3})", self.x, self.y)
     }
 }
 
-impl Drop for Font {
-    fn drop(&mut self) {
-        unsafe {
-            c_api::delete_font(self.font);
-        }
+impl Add for PixelLocation {
+    type Output = PixelLocation;
+
+    fn add(self, other: PixelLocation) -> PixelLocation {
+        PixelLocation { x: self.x + other.x, y: self.y + other.y }
+    }
+}
+
+impl Sub for PixelLocation {
+    type Output = PixelLocation;
+
+    fn sub(self, other: PixelLocation) -> PixelLocation {
+        PixelLocation { x: self.x - other.x, y: self.y - other.y }
+    }
+}
+
+/// Scaling a whole pixel location yields a sub-pixel point, so no rounding
+/// happens behind your back. Use `PointF::to_pixel` to get back.
+impl Mul<f32> for PixelLocation {
+    type Output = PointF;
+
+    fn mul(self, scale: f32) -> PointF {
+        PointF::from(self) * scale
     }
 }
 
 /*
- * Pixel Location
+ * Rectangle
  */
 
-#[derive(Copy, Clone)]
-pub struct PixelLocation {
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
     pub x: i32,
     pub y: i32,
+    pub width: i32,
+    pub height: i32,
 }
 
-impl PixelLocation {
-    pub fn from_relative(x: f32, y: f32, canvas: &Canvas) -> PixelLocation {
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    /// The rectangle covering the whole canvas.
+    pub fn from_canvas(canvas: &Canvas) -> Rect {
+        let (width, height) = canvas.get_size();
+        Rect { x: 0, y: 0, width, height }
+    }
+
+    pub fn contains(&self, pixel: &PixelLocation) -> bool {
+        pixel.x >= self.x && pixel.y >= self.y
+            && pixel.x < self.x + self.width && pixel.y < self.y + self.height
+    }
+
+    /// The part covered by both rectangles, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= left || bottom <= top {
+            return None;
+        }
+        Some(Rect { x: left, y: top, width: right - left, height: bottom - top })
+    }
+}
+
+/*
+ * Sub-pixel Point
+ */
+
+/// How a sub-pixel coordinate is snapped onto the pixel grid.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Round half away from zero.
+    Nearest,
+    /// Round towards negative infinity.
+    Floor,
+    /// Round towards positive infinity.
+    Ceil,
+    /// Round towards zero (what an `as i32` cast does).
+    Truncate,
+}
+
+impl Rounding {
+    fn apply(&self, value: f32) -> i32 {
+        match self {
+            Rounding::Nearest => value.round() as i32,
+            Rounding::Floor => value.floor() as i32,
+            Rounding::Ceil => value.ceil() as i32,
+            Rounding::Truncate => value.trunc() as i32,
+        }
+    }
+}
+
+/// A location on the canvas with sub-pixel precision, for smooth motion.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PointF {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl PointF {
+    pub fn new(x: f32, y: f32) -> PointF {
+        PointF { x, y }
+    }
+
+    /// Like `PixelLocation::from_relative`, but keeps the fractional part.
+    pub fn from_relative(x: f32, y: f32, canvas: &Canvas) -> PointF {
         let (xsize, ysize) = canvas.get_size();
 
+        PointF {
+            x: xsize as f32 * x,
+            y: ysize as f32 * y,
+        }
+    }
+
+    /// Snaps this point onto the pixel grid using the given rounding mode.
+    pub fn to_pixel(&self, rounding: Rounding) -> PixelLocation {
         PixelLocation {
-            x: (xsize as f32 * x) as i32,
-            y: (ysize as f32 * y) as i32,
+            x: rounding.apply(self.x),
+            y: rounding.apply(self.y),
         }
     }
 }
 
-impl std::fmt::Display for PixelLocation {
+impl From<PixelLocation> for PointF {
+    fn from(pixel: PixelLocation) -> PointF {
+        PointF { x: pixel.x as f32, y: pixel.y as f32 }
+    }
+}
+
+impl Add for PointF {
+    type Output = PointF;
+
+    fn add(self, other: PointF) -> PointF {
+        PointF { x: self.x + other.x, y: self.y + other.y }
+    }
+}
+
+impl Sub for PointF {
+    type Output = PointF;
+
+    fn sub(self, other: PointF) -> PointF {
+        PointF { x: self.x - other.x, y: self.y - other.y }
+    }
+}
+
+impl Mul<f32> for PointF {
+    type Output = PointF;
+
+    fn mul(self, scale: f32) -> PointF {
+        PointF { x: self.x * scale, y: self.y * scale }
+    }
+}
+
+impl std::fmt::Display for PointF {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "({:<3}, {:<3})", self.x, self.y)
+        write!(f, "({:<6.2}, {:<6.2})", self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_rounding() {
+        let p = PointF::new(-1.5, 2.5);
+        assert_eq!(p.to_pixel(Rounding::Nearest), PixelLocation { x: -2, y: 3 });
+        assert_eq!(p.to_pixel(Rounding::Floor), PixelLocation { x: -2, y: 2 });
+        assert_eq!(p.to_pixel(Rounding::Ceil), PixelLocation { x: -1, y: 3 });
+        assert_eq!(p.to_pixel(Rounding::Truncate), PixelLocation { x: -1, y: 2 });
+    }
+
+    #[test]
+    fn point_arithmetic() {
+        let a = PixelLocation { x: 1, y: 2 };
+        let b = PixelLocation { x: 3, y: -4 };
+        assert_eq!(a + b, PixelLocation { x: 4, y: -2 });
+        assert_eq!(a - b, PixelLocation { x: -2, y: 6 });
+        assert_eq!(a * 0.5, PointF::new(0.5, 1.0));
+        assert_eq!(PointF::from(a) + PointF::new(0.25, 0.25), PointF::new(1.25, 2.25));
+        assert_eq!(PointF::new(1.0, 1.0) - PointF::new(0.5, 2.0), PointF::new(0.5, -1.0));
     }
 }
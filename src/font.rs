@@ -0,0 +1,985 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Code point drawn in place of characters a font has no glyph for, unless
+/// the font names its own `DEFAULT_CHAR`.
+pub const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+/// Largest glyph width or height we accept; anything bigger is a broken
+/// font, not a real glyph.
+pub const MAX_GLYPH_SIZE: i32 = 4096;
+
+/*
+ * Errors
+ */
+
+#[derive(Debug)]
+pub enum FontError {
+    /// The font file could not be read.
+    Io(io::Error),
+    /// The file is not in a format we know how to load.
+    UnsupportedFormat,
+    /// The font data is malformed. `line` is 1-based, or 0 for formats
+    /// that aren't line based.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Io(err) => write!(f, "could not read font: {}", err),
+            FontError::UnsupportedFormat => write!(f, "unsupported font format"),
+            FontError::Parse { line: 0, message } => write!(f, "{}", message),
+            FontError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for FontError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FontError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FontError {
+    fn from(err: io::Error) -> FontError {
+        FontError::Io(err)
+    }
+}
+
+/*
+ * Glyph
+ */
+
+/// A bounding box in BDF conventions: the offsets locate the bottom left
+/// corner relative to the glyph origin on the baseline, with y pointing up.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub width: i32,
+    pub height: i32,
+    pub x_offset: i32,
+    pub y_offset: i32,
+}
+
+impl BoundingBox {
+    /// The smallest box enclosing both boxes.
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        let left = self.x_offset.min(other.x_offset);
+        let bottom = self.y_offset.min(other.y_offset);
+        let right = (self.x_offset + self.width).max(other.x_offset + other.width);
+        let top = (self.y_offset + self.height).max(other.y_offset + other.height);
+        BoundingBox { width: right - left, height: top - bottom, x_offset: left, y_offset: bottom }
+    }
+
+    /// Number of pixels in the box, or `None` if a side is negative or
+    /// longer than `MAX_GLYPH_SIZE`.
+    fn pixel_count(&self) -> Option<usize> {
+        let side = |length: i32| if (0..=MAX_GLYPH_SIZE).contains(&length) { Some(length as usize) } else { None };
+        Some(side(self.width)? * side(self.height)?)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Glyph {
+    /// Horizontal distance to the origin of the next glyph.
+    pub advance: i32,
+    pub bbx: BoundingBox,
+    /// One coverage byte per pixel of `bbx`, rows top to bottom.
+    coverage: Vec<u8>,
+}
+
+impl Glyph {
+    /// Makes a glyph from one coverage byte per pixel of `bbx`, rows top to
+    /// bottom.
+    pub fn new(advance: i32, bbx: BoundingBox, coverage: Vec<u8>) -> Result<Glyph, &'static str> {
+        let pixels = bbx.pixel_count().ok_or("bounding box too large")?;
+        if coverage.len() != pixels {
+            return Err("coverage doesn't match the bounding box");
+        }
+        Ok(Glyph { advance, bbx, coverage })
+    }
+
+    /// Coverage of a pixel inside the bounding box, 0 (off) to 255 (fully on).
+    /// `x` and `y` count from the top left corner of the box.
+    pub fn coverage(&self, x: i32, y: i32) -> u8 {
+        if x < 0 || y < 0 || x >= self.bbx.width || y >= self.bbx.height {
+            return 0;
+        }
+        self.coverage[(y * self.bbx.width + x) as usize]
+    }
+
+    pub fn is_set(&self, x: i32, y: i32) -> bool {
+        self.coverage(x, y) > 0
+    }
+}
+
+/*
+ * Text Metrics
+ */
+
+/// The size of a single line of text, as laid out by `Canvas::draw_text`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextMetrics {
+    /// Horizontal advance, i.e. what `Canvas::draw_text` returns.
+    pub width: i32,
+    pub height: i32,
+    pub ascent: i32,
+    pub descent: i32,
+}
+
+/*
+ * Font
+ */
+
+pub struct Font {
+    name: String,
+    bbx: BoundingBox,
+    ascent: i32,
+    descent: i32,
+    replacement: char,
+    glyphs: HashMap<char, Glyph>,
+}
+
+impl Font {
+    /// Loads a BDF font from disk.
+    pub fn new(bdf_filepath: &Path) -> Result<Font, FontError> {
+        match bdf_filepath.extension() {
+            Some(ext) if ext == "bdf" => {}
+            _ => return Err(FontError::UnsupportedFormat),
+        }
+
+        Font::from_bytes(&fs::read(bdf_filepath)?)
+    }
+
+    /// Parses a BDF font held in memory, e.g. one embedded with `include_bytes!`.
+    pub fn from_bytes(bdf: &[u8]) -> Result<Font, FontError> {
+        // BDF is ASCII, but property strings in the wild are often Latin-1
+        let text = String::from_utf8_lossy(bdf);
+        BdfParser::default().parse(&text)
+    }
+
+    /// Assembles a font from individual glyphs, e.g. cut from a sprite sheet.
+    /// A line of text is `ascent + descent` pixels tall.
+    pub fn from_glyphs(name: &str, ascent: i32, descent: i32, glyphs: HashMap<char, Glyph>) -> Font {
+        let width = glyphs.values().map(|glyph| glyph.advance).max().unwrap_or(0);
+
+        Font {
+            name: name.to_string(),
+            bbx: BoundingBox { width, height: ascent + descent, x_offset: 0, y_offset: -descent },
+            ascent,
+            descent,
+            replacement: REPLACEMENT_CHARACTER,
+            glyphs,
+        }
+    }
+
+    /// Writes the font as BDF, loadable by `Font::new` and the C library.
+    /// BDF is 1-bit, so antialiased pixels are on from half coverage.
+    pub fn write_bdf<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let name = if self.name.is_empty() { "ledmatrix" } else { &self.name };
+        let size = self.bbx.height.max(1);
+        let bbx = &self.bbx;
+
+        writeln!(out, "STARTFONT 2.1")?;
+        writeln!(out, "FONT {}", name)?;
+        writeln!(out, "SIZE {} 75 75", size)?;
+        writeln!(out, "FONTBOUNDINGBOX {} {} {} {}", bbx.width, bbx.height, bbx.x_offset, bbx.y_offset)?;
+        writeln!(out, "STARTPROPERTIES 3")?;
+        writeln!(out, "FONT_ASCENT {}", self.ascent)?;
+        writeln!(out, "FONT_DESCENT {}", self.descent)?;
+        writeln!(out, "DEFAULT_CHAR {}", self.replacement as u32)?;
+        writeln!(out, "ENDPROPERTIES")?;
+        writeln!(out, "CHARS {}", self.glyphs.len())?;
+
+        let mut chars: Vec<&char> = self.glyphs.keys().collect();
+        chars.sort();
+        for c in chars {
+            let glyph = &self.glyphs[c];
+            let bbx = &glyph.bbx;
+            writeln!(out, "STARTCHAR U+{:04X}", *c as u32)?;
+            writeln!(out, "ENCODING {}", *c as u32)?;
+            // scalable width in 1/1000ths of the point size, at 72pt per inch
+            writeln!(out, "SWIDTH {} 0", glyph.advance * 72_000 / (size * 75))?;
+            writeln!(out, "DWIDTH {} 0", glyph.advance)?;
+            writeln!(out, "BBX {} {} {} {}", bbx.width, bbx.height, bbx.x_offset, bbx.y_offset)?;
+            writeln!(out, "BITMAP")?;
+            for y in 0..bbx.height {
+                let mut row = String::new();
+                for byte in 0..(bbx.width + 7) / 8 {
+                    let mut bits = 0u8;
+                    for bit in 0..8 {
+                        if glyph.coverage(byte * 8 + bit, y) >= 128 {
+                            bits |= 0x80 >> bit;
+                        }
+                    }
+                    row.push_str(&format!("{:02X}", bits));
+                }
+                writeln!(out, "{}", row)?;
+            }
+            writeln!(out, "ENDCHAR")?;
+        }
+
+        writeln!(out, "ENDFONT")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The bounding box enclosing every glyph of the font.
+    pub fn bounding_box(&self) -> BoundingBox {
+        self.bbx
+    }
+
+    /// Pixels from the baseline to the top of the tallest glyph.
+    pub fn ascent(&self) -> i32 {
+        self.ascent
+    }
+
+    /// Pixels from the baseline to the bottom of the lowest glyph.
+    pub fn descent(&self) -> i32 {
+        self.descent
+    }
+
+    /// Height of a line of text in pixels.
+    pub fn height(&self) -> i32 {
+        self.bbx.height
+    }
+
+    /// Pixels from the top of a line of text down to its baseline.
+    pub fn baseline(&self) -> i32 {
+        self.bbx.height + self.bbx.y_offset
+    }
+
+    /// Advance of a single character, 0 if the font cannot draw it.
+    pub fn char_width(&self, c: char) -> i32 {
+        self.glyph_or_replacement(c).map_or(0, |glyph| glyph.advance)
+    }
+
+    /// Measures `utf8_text` without drawing it, so it can be aligned first.
+    pub fn measure(&self, utf8_text: &str, kerning_offset: i32) -> TextMetrics {
+        let width = utf8_text.chars().map(|c| self.char_width(c) + kerning_offset).sum();
+
+        TextMetrics {
+            width,
+            height: self.height(),
+            ascent: self.ascent,
+            descent: self.descent,
+        }
+    }
+
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c)
+    }
+
+    /// The glyph for `c`, falling back to the font's replacement character.
+    pub(crate) fn glyph_or_replacement(&self, c: char) -> Option<&Glyph> {
+        self.glyph(c).or_else(|| self.glyph(self.replacement))
+    }
+
+    /// The character drawn in place of ones the font has no glyph for.
+    pub fn replacement(&self) -> char {
+        self.replacement
+    }
+
+    pub fn set_replacement(&mut self, c: char) {
+        self.replacement = c;
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.glyphs.contains_key(&c)
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyphs.len()
+    }
+}
+
+/*
+ * Font Stack
+ */
+
+/// Several fonts in priority order, for text mixing scripts that no single
+/// BDF font covers (e.g. a Latin font, then a CJK font, then symbols).
+pub struct FontStack {
+    fonts: Vec<Font>,
+    replacement: char,
+}
+
+impl FontStack {
+    pub fn new() -> FontStack {
+        FontStack {
+            fonts: Vec::new(),
+            replacement: REPLACEMENT_CHARACTER,
+        }
+    }
+
+    /// Adds a font with lower priority than all fonts already in the stack.
+    pub fn push(&mut self, font: Font) {
+        self.fonts.push(font);
+    }
+
+    /// Sets the character drawn when no font in the stack has a glyph.
+    pub fn set_replacement(&mut self, c: char) {
+        self.replacement = c;
+    }
+
+    /// The first font containing `c`.
+    pub fn font_for(&self, c: char) -> Option<&Font> {
+        self.fonts.iter().find(|font| font.contains(c))
+    }
+
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.font_for(c).and_then(|font| font.glyph(c))
+    }
+
+    /// Merges the stack into a single `Font` usable anywhere a font is, where
+    /// each code point maps to the glyph of the first font containing it.
+    ///
+    /// Glyphs are positioned relative to the baseline, so mixing fonts keeps
+    /// them aligned; the line grows to fit the tallest font.
+    pub fn into_font(self) -> Font {
+        let mut fonts = self.fonts.into_iter();
+        let mut merged = match fonts.next() {
+            Some(font) => font,
+            None => Font {
+                name: String::new(),
+                bbx: BoundingBox::default(),
+                ascent: 0,
+                descent: 0,
+                replacement: self.replacement,
+                glyphs: HashMap::new(),
+            },
+        };
+
+        for font in fonts {
+            merged.bbx = merged.bbx.union(&font.bbx);
+            merged.ascent = merged.ascent.max(font.ascent);
+            merged.descent = merged.descent.max(font.descent);
+
+            for (c, glyph) in font.glyphs {
+                merged.glyphs.entry(c).or_insert(glyph);
+            }
+        }
+
+        merged.replacement = self.replacement;
+        merged
+    }
+}
+
+impl Default for FontStack {
+    fn default() -> FontStack {
+        FontStack::new()
+    }
+}
+
+/*
+ * Built-in Fonts
+ */
+
+/// The fonts bundled with the C library, embedded into the binary by the
+/// `fonts` feature so deployed programs don't need the font files.
+#[cfg(feature = "fonts")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuiltinFont {
+    Font4x6,
+    Font5x7,
+    Font5x8,
+    Font6x9,
+    Font6x10,
+    Font6x12,
+    Font6x13,
+    Font6x13Bold,
+    Font7x13,
+    Font7x13Bold,
+    Font7x14,
+    Font8x13,
+    Font8x13Bold,
+    Font9x15,
+    Font9x15Bold,
+    Font9x18,
+    Font9x18Bold,
+    Font10x20,
+    TomThumb,
+}
+
+#[cfg(feature = "fonts")]
+macro_rules! bundled_font {
+    ($file:expr) => {
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/rpi-rgb-led-matrix/fonts/", $file))
+    };
+}
+
+#[cfg(feature = "fonts")]
+impl BuiltinFont {
+    pub const ALL: [BuiltinFont; 19] = [
+        BuiltinFont::Font4x6,
+        BuiltinFont::Font5x7,
+        BuiltinFont::Font5x8,
+        BuiltinFont::Font6x9,
+        BuiltinFont::Font6x10,
+        BuiltinFont::Font6x12,
+        BuiltinFont::Font6x13,
+        BuiltinFont::Font6x13Bold,
+        BuiltinFont::Font7x13,
+        BuiltinFont::Font7x13Bold,
+        BuiltinFont::Font7x14,
+        BuiltinFont::Font8x13,
+        BuiltinFont::Font8x13Bold,
+        BuiltinFont::Font9x15,
+        BuiltinFont::Font9x15Bold,
+        BuiltinFont::Font9x18,
+        BuiltinFont::Font9x18Bold,
+        BuiltinFont::Font10x20,
+        BuiltinFont::TomThumb,
+    ];
+
+    /// The raw BDF data of the font.
+    pub fn bdf(&self) -> &'static [u8] {
+        match self {
+            BuiltinFont::Font4x6 => bundled_font!("4x6.bdf"),
+            BuiltinFont::Font5x7 => bundled_font!("5x7.bdf"),
+            BuiltinFont::Font5x8 => bundled_font!("5x8.bdf"),
+            BuiltinFont::Font6x9 => bundled_font!("6x9.bdf"),
+            BuiltinFont::Font6x10 => bundled_font!("6x10.bdf"),
+            BuiltinFont::Font6x12 => bundled_font!("6x12.bdf"),
+            BuiltinFont::Font6x13 => bundled_font!("6x13.bdf"),
+            BuiltinFont::Font6x13Bold => bundled_font!("6x13B.bdf"),
+            BuiltinFont::Font7x13 => bundled_font!("7x13.bdf"),
+            BuiltinFont::Font7x13Bold => bundled_font!("7x13B.bdf"),
+            BuiltinFont::Font7x14 => bundled_font!("7x14.bdf"),
+            BuiltinFont::Font8x13 => bundled_font!("8x13.bdf"),
+            BuiltinFont::Font8x13Bold => bundled_font!("8x13B.bdf"),
+            BuiltinFont::Font9x15 => bundled_font!("9x15.bdf"),
+            BuiltinFont::Font9x15Bold => bundled_font!("9x15B.bdf"),
+            BuiltinFont::Font9x18 => bundled_font!("9x18.bdf"),
+            BuiltinFont::Font9x18Bold => bundled_font!("9x18B.bdf"),
+            BuiltinFont::Font10x20 => bundled_font!("10x20.bdf"),
+            BuiltinFont::TomThumb => bundled_font!("tom-thumb.bdf"),
+        }
+    }
+}
+
+#[cfg(feature = "fonts")]
+impl Font {
+    /// Parses one of the embedded fonts. Each call parses afresh, so load
+    /// it once and keep it around.
+    pub fn builtin(font: BuiltinFont) -> Font {
+        Font::from_bytes(font.bdf()).expect("bundled fonts are valid BDF")
+    }
+}
+
+/*
+ * TrueType
+ */
+
+/// How outlines are turned into LED pixels.
+#[cfg(feature = "ttf")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Rasterization {
+    /// Partially covered pixels are drawn dimmed.
+    Antialiased,
+    /// Pixels are on if their coverage (0-255) reaches the threshold, for
+    /// crisp 1-bit text.
+    Threshold(u8),
+}
+
+#[cfg(feature = "ttf")]
+pub struct TtfOptions {
+    /// Size of the font in pixels, from descender to ascender.
+    pub pixel_size: f32,
+    pub rasterization: Rasterization,
+    /// Characters to rasterize; `None` rasterizes every character in the
+    /// font, which takes a lot of memory for large CJK fonts.
+    pub chars: Option<String>,
+}
+
+#[cfg(feature = "ttf")]
+impl TtfOptions {
+    pub fn new(pixel_size: f32) -> TtfOptions {
+        TtfOptions {
+            pixel_size,
+            rasterization: Rasterization::Antialiased,
+            chars: None,
+        }
+    }
+}
+
+#[cfg(feature = "ttf")]
+impl Font {
+    /// Loads a TrueType or OpenType font from disk, see `Font::from_ttf`.
+    pub fn from_ttf_file(ttf_filepath: &Path, options: &TtfOptions) -> Result<Font, FontError> {
+        Font::from_ttf(&fs::read(ttf_filepath)?, options)
+    }
+
+    /// Rasterizes a TrueType or OpenType font at a fixed pixel size, giving
+    /// a font that draws just like a BDF one.
+    pub fn from_ttf(ttf: &[u8], options: &TtfOptions) -> Result<Font, FontError> {
+        use ab_glyph::{Font as _, FontRef, ScaleFont};
+
+        let ttf_font = FontRef::try_from_slice(ttf).map_err(|_| FontError::UnsupportedFormat)?;
+        let scaled = ttf_font.as_scaled(options.pixel_size);
+        let chars: Vec<(ab_glyph::GlyphId, char)> = match &options.chars {
+            Some(chars) => chars.chars().map(|c| (ttf_font.glyph_id(c), c)).collect(),
+            None => ttf_font.codepoint_ids().collect(),
+        };
+
+        let glyph_error = |c: char, message: &str| FontError::Parse { line: 0, message: format!("glyph {:?}: {}", c, message) };
+        let mut glyphs = HashMap::new();
+        for (id, c) in chars {
+            // glyph 0 is what fonts draw for characters they don't have
+            if id.0 == 0 && c != REPLACEMENT_CHARACTER {
+                continue;
+            }
+            let advance = scaled.h_advance(id).round() as i32;
+
+            let glyph = match scaled.outline_glyph(id.with_scale(options.pixel_size)) {
+                Some(outline) => {
+                    let bounds = outline.px_bounds();
+                    let bbx = BoundingBox {
+                        width: bounds.width() as i32,
+                        height: bounds.height() as i32,
+                        x_offset: bounds.min.x as i32,
+                        y_offset: -(bounds.max.y as i32),
+                    };
+                    let pixels = bbx.pixel_count().ok_or_else(|| glyph_error(c, "bounding box too large"))?;
+                    let mut coverage = vec![0; pixels];
+                    outline.draw(|x, y, amount| {
+                        let index = y as usize * bbx.width as usize + x as usize;
+                        let amount = (amount.min(1.0) * 255.0).round() as u8;
+                        if let Some(pixel) = coverage.get_mut(index) {
+                            *pixel = match options.rasterization {
+                                Rasterization::Antialiased => amount,
+                                Rasterization::Threshold(threshold) if amount >= threshold => 255,
+                                Rasterization::Threshold(_) => 0,
+                            };
+                        }
+                    });
+                    Glyph::new(advance, bbx, coverage).map_err(|err| glyph_error(c, err))?
+                }
+                // e.g. the space character
+                None => Glyph { advance, bbx: BoundingBox::default(), coverage: Vec::new() },
+            };
+            glyphs.insert(c, glyph);
+        }
+
+        let ascent = scaled.ascent().ceil() as i32;
+        let descent = (-scaled.descent()).ceil() as i32;
+        Ok(Font::from_glyphs("", ascent, descent, glyphs))
+    }
+}
+
+/*
+ * BDF parsing
+ */
+
+#[derive(Default)]
+struct BdfParser {
+    line: usize,
+}
+
+impl BdfParser {
+    fn error(&self, message: &str) -> FontError {
+        FontError::Parse { line: self.line, message: message.to_string() }
+    }
+
+    fn numbers(&self, args: &[&str], count: usize) -> Result<Vec<i32>, FontError> {
+        if args.len() < count {
+            return Err(self.error("missing numeric arguments"));
+        }
+        args[..count]
+            .iter()
+            .map(|arg| arg.parse::<i32>().map_err(|_| self.error("expected an integer")))
+            .collect()
+    }
+
+    fn bounding_box(&self, args: &[&str]) -> Result<BoundingBox, FontError> {
+        let n = self.numbers(args, 4)?;
+        if n[0] < 0 || n[1] < 0 {
+            return Err(self.error("negative bounding box size"));
+        }
+        if n[0] > MAX_GLYPH_SIZE || n[1] > MAX_GLYPH_SIZE {
+            return Err(self.error("bounding box too large"));
+        }
+        Ok(BoundingBox { width: n[0], height: n[1], x_offset: n[2], y_offset: n[3] })
+    }
+
+    fn parse(mut self, text: &str) -> Result<Font, FontError> {
+        let mut lines = text.lines();
+        let mut font = Font {
+            name: String::new(),
+            bbx: BoundingBox::default(),
+            ascent: 0,
+            descent: 0,
+            replacement: REPLACEMENT_CHARACTER,
+            glyphs: HashMap::new(),
+        };
+        let mut ascent = None;
+        let mut descent = None;
+        let mut default_advance = None;
+        let mut started = false;
+
+        while let Some(line) = lines.next() {
+            self.line += 1;
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let args: Vec<&str> = words.collect();
+
+            if !started {
+                if keyword != "STARTFONT" {
+                    return Err(self.error("not a BDF font, expected STARTFONT"));
+                }
+                started = true;
+                continue;
+            }
+
+            match keyword {
+                "FONT" => font.name = line.trim_start()[4..].trim().to_string(),
+                "FONTBOUNDINGBOX" => font.bbx = self.bounding_box(&args)?,
+                "FONT_ASCENT" => ascent = Some(self.numbers(&args, 1)?[0]),
+                "FONT_DESCENT" => descent = Some(self.numbers(&args, 1)?[0]),
+                "DEFAULT_CHAR" => {
+                    let code = self.numbers(&args, 1)?[0] as u32;
+                    font.replacement = std::char::from_u32(code).unwrap_or(REPLACEMENT_CHARACTER);
+                }
+                "DWIDTH" => default_advance = Some(self.numbers(&args, 1)?[0]),
+                "STARTCHAR" => {
+                    if let Some((c, glyph)) = self.parse_glyph(&mut lines, default_advance)? {
+                        font.glyphs.insert(c, glyph);
+                    }
+                }
+                "ENDFONT" => break,
+                _ => {}
+            }
+        }
+
+        if !started {
+            return Err(self.error("empty font"));
+        }
+
+        font.ascent = ascent.unwrap_or(font.bbx.height + font.bbx.y_offset);
+        font.descent = descent.unwrap_or(-font.bbx.y_offset);
+        Ok(font)
+    }
+
+    /// Parses everything up to and including ENDCHAR. Glyphs without a
+    /// Unicode encoding are consumed but not returned.
+    fn parse_glyph<'a, I: Iterator<Item = &'a str>>(
+        &mut self,
+        lines: &mut I,
+        default_advance: Option<i32>,
+    ) -> Result<Option<(char, Glyph)>, FontError> {
+        let mut encoding = None;
+        let mut advance = default_advance;
+        let mut bbx = None;
+
+        while let Some(line) = lines.next() {
+            self.line += 1;
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let args: Vec<&str> = words.collect();
+
+            match keyword {
+                // "ENCODING -1 <n>" marks a glyph outside the standard encoding
+                "ENCODING" => {
+                    let code = self.numbers(&args, 1)?[0];
+                    encoding = if code < 0 { None } else { std::char::from_u32(code as u32) };
+                }
+                "DWIDTH" => advance = Some(self.numbers(&args, 1)?[0]),
+                "BBX" => bbx = Some(self.bounding_box(&args)?),
+                "BITMAP" => {
+                    let bbx = bbx.ok_or_else(|| self.error("BITMAP before BBX"))?;
+                    let coverage = self.parse_bitmap(lines, &bbx)?;
+                    let glyph = Glyph { advance: advance.unwrap_or(bbx.width), bbx, coverage };
+                    return Ok(encoding.map(|c| (c, glyph)));
+                }
+                "ENDCHAR" => return Ok(None),
+                _ => {}
+            }
+        }
+
+        Err(self.error("unterminated glyph"))
+    }
+
+    fn parse_bitmap<'a, I: Iterator<Item = &'a str>>(
+        &mut self,
+        lines: &mut I,
+        bbx: &BoundingBox,
+    ) -> Result<Vec<u8>, FontError> {
+        let mut coverage = Vec::with_capacity((bbx.width * bbx.height) as usize);
+
+        for line in lines.by_ref() {
+            self.line += 1;
+            let row = line.trim();
+            if row == "ENDCHAR" {
+                break;
+            }
+            if coverage.len() >= (bbx.width * bbx.height) as usize {
+                return Err(self.error("more bitmap rows than the BBX height"));
+            }
+
+            // each row is left aligned and padded to a whole number of bytes
+            let mut bits = row.chars().map(|c| c.to_digit(16));
+            let mut x = 0;
+            while x < bbx.width {
+                let nibble = match bits.next() {
+                    Some(Some(nibble)) => nibble,
+                    Some(None) => return Err(self.error("invalid hex digit in bitmap")),
+                    None => 0,
+                };
+                for bit in (0..4).rev() {
+                    if x < bbx.width {
+                        coverage.push(if nibble & (1 << bit) != 0 { 255 } else { 0 });
+                        x += 1;
+                    }
+                }
+            }
+        }
+
+        // tolerate truncated bitmaps the way most renderers do
+        coverage.resize((bbx.width * bbx.height) as usize, 0);
+        Ok(coverage)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const TEST_BDF: &str = "STARTFONT 2.1
+FONT -Test-Fixed-Medium-R-Normal--8-80-75-75-C-50-ISO10646-1
+SIZE 8 75 75
+FONTBOUNDINGBOX 5 8 0 -1
+STARTPROPERTIES 2
+FONT_ASCENT 7
+FONT_DESCENT 1
+ENDPROPERTIES
+CHARS 4
+STARTCHAR space
+ENCODING 32
+DWIDTH 5 0
+BBX 0 0 0 0
+BITMAP
+ENDCHAR
+STARTCHAR A
+ENCODING 65
+SWIDTH 640 0
+DWIDTH 5 0
+BBX 5 8 0 -1
+BITMAP
+00
+20
+50
+88
+F8
+88
+88
+00
+ENDCHAR
+STARTCHAR period
+ENCODING 46
+DWIDTH 5 0
+BBX 1 1 2 0
+BITMAP
+80
+ENDCHAR
+STARTCHAR unencoded
+ENCODING -1 7
+DWIDTH 5 0
+BBX 1 1 0 0
+BITMAP
+80
+ENDCHAR
+ENDFONT
+";
+
+    #[test]
+    fn parse_metrics() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        assert_eq!(font.name(), "-Test-Fixed-Medium-R-Normal--8-80-75-75-C-50-ISO10646-1");
+        assert_eq!(font.ascent(), 7);
+        assert_eq!(font.descent(), 1);
+        assert_eq!(font.bounding_box(), BoundingBox { width: 5, height: 8, x_offset: 0, y_offset: -1 });
+        assert_eq!(font.glyph_count(), 3);
+        assert!(!font.contains('B'));
+    }
+
+    #[test]
+    fn measure_text() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        assert_eq!(font.height(), 8);
+        assert_eq!(font.baseline(), 7);
+
+        let metrics = font.measure("A.A", 1);
+        assert_eq!(metrics, TextMetrics { width: 18, height: 8, ascent: 7, descent: 1 });
+        // no glyph and no replacement character
+        assert_eq!(font.measure("B", 0).width, 0);
+    }
+
+    #[test]
+    fn parse_bitmap() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let a = font.glyph('A').unwrap();
+        assert_eq!(a.advance, 5);
+        assert!(a.is_set(2, 1));
+        assert!(!a.is_set(1, 1));
+        assert!((0..5).all(|x| a.is_set(x, 4)));
+        assert!(!a.is_set(5, 4));
+
+        let period = font.glyph('.').unwrap();
+        assert_eq!(period.bbx, BoundingBox { width: 1, height: 1, x_offset: 2, y_offset: 0 });
+        assert_eq!(period.coverage(0, 0), 255);
+    }
+
+    #[test]
+    fn bdf_round_trip() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let mut bdf = Vec::new();
+        font.write_bdf(&mut bdf).unwrap();
+        let copy = Font::from_bytes(&bdf).unwrap();
+
+        assert_eq!(copy.name(), font.name());
+        assert_eq!(copy.bounding_box(), font.bounding_box());
+        assert_eq!((copy.ascent(), copy.descent()), (font.ascent(), font.descent()));
+        assert_eq!(copy.glyph_count(), font.glyph_count());
+        for c in " A.".chars() {
+            let (a, b) = (font.glyph(c).unwrap(), copy.glyph(c).unwrap());
+            assert_eq!((a.advance, a.bbx, &a.coverage), (b.advance, b.bbx, &b.coverage));
+        }
+    }
+
+    #[test]
+    fn font_stack_fallback() {
+        let tall = TEST_BDF
+            .replace("FONTBOUNDINGBOX 5 8 0 -1", "FONTBOUNDINGBOX 8 12 0 -2")
+            .replace("ENCODING 65", "ENCODING 12354")
+            .replace("ENCODING 46", "ENCODING 63");
+        let mut stack = FontStack::new();
+        stack.push(Font::from_bytes(TEST_BDF.as_bytes()).unwrap());
+        stack.push(Font::from_bytes(tall.as_bytes()).unwrap());
+        stack.set_replacement('?');
+        assert_eq!(stack.font_for('A').unwrap().height(), 8);
+        assert_eq!(stack.font_for('\u{3042}').unwrap().height(), 12);
+        assert!(stack.font_for('B').is_none());
+
+        let font = stack.into_font();
+        assert_eq!(font.bounding_box(), BoundingBox { width: 8, height: 12, x_offset: 0, y_offset: -2 });
+        assert_eq!(font.glyph(' ').unwrap().bbx.width, 0);
+        assert!(font.contains('A') && font.contains('\u{3042}') && font.contains('?'));
+        assert_eq!(font.measure("B", 0).width, 5);
+    }
+
+    /// A TrueType font with 1000 units per em, ascent 800 and descent 200,
+    /// whose only glyph is 'A': a 400 by 600 square at x 100, 600 wide.
+    #[cfg(feature = "ttf")]
+    fn square_ttf() -> Vec<u8> {
+        let be16 = |values: &[i16]| values.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<u8>>();
+        let be32 = |values: &[u32]| values.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<u8>>();
+
+        // glyph 0 is empty, glyph 1 one contour of four on-curve points
+        let mut glyf = be16(&[1, 100, 0, 500, 600, 3, 0]);
+        glyf.extend_from_slice(&[1; 4]);
+        glyf.extend(be16(&[100, 0, 400, 0, 0, 600, 0, -600]));
+
+        let mut head = be32(&[0x10000, 0x10000, 0, 0x5f0f_3cf5]);
+        head.extend(be16(&[0, 1000]));
+        head.extend([0; 16]);
+        head.extend(be16(&[100, 0, 500, 600, 0, 8, 2, 0, 0]));
+        let mut hhea = be32(&[0x10000]);
+        hhea.extend(be16(&[800, -200, 0, 600, 0, 0, 500, 1, 0, 0, 0, 0, 0, 0, 0, 2]));
+        let mut maxp = be32(&[0x5000]);
+        maxp.extend(be16(&[2]));
+        let hmtx = be16(&[0, 0, 600, 100]);
+        let loca = be16(&[0, 0, glyf.len() as i16 / 2]);
+        let mut cmap = be16(&[0, 1, 3, 10]);
+        cmap.extend(be32(&[12]));
+        cmap.extend(be16(&[12, 0]));
+        cmap.extend(be32(&[28, 0, 1, 'A' as u32, 'A' as u32, 1]));
+
+        let tables = [
+            (b"cmap", cmap),
+            (b"glyf", glyf),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", hmtx),
+            (b"loca", loca),
+            (b"maxp", maxp),
+        ];
+        let mut ttf = be32(&[0x10000]);
+        ttf.extend(be16(&[tables.len() as i16, 0, 0, 0]));
+        let mut data = Vec::new();
+        let data_start = ttf.len() + 16 * tables.len();
+        for (tag, table) in tables.iter() {
+            ttf.extend_from_slice(&tag[..]);
+            ttf.extend(be32(&[0, (data_start + data.len()) as u32, table.len() as u32]));
+            data.extend_from_slice(table);
+            data.resize((data.len() + 3) / 4 * 4, 0);
+        }
+        ttf.extend(data);
+        ttf
+    }
+
+    #[cfg(feature = "ttf")]
+    #[test]
+    fn rasterizes_ttf() {
+        let mut options = TtfOptions::new(10.0);
+        options.chars = Some("A".to_string());
+        let font = Font::from_ttf(&square_ttf(), &options).unwrap();
+        assert_eq!((font.baseline(), font.height()), (8, 10));
+
+        let glyph = font.glyph('A').unwrap();
+        assert_eq!(glyph.advance, 6);
+        assert_eq!(glyph.bbx, BoundingBox { width: 4, height: 6, x_offset: 1, y_offset: 0 });
+        assert_eq!(glyph.coverage(0, 0), 255);
+        assert_eq!(glyph.coverage(3, 5), 255);
+        assert_eq!(glyph.coverage(4, 0), 0);
+
+        options.pixel_size = 100_000.0;
+        assert!(matches!(Font::from_ttf(&square_ttf(), &options), Err(FontError::Parse { .. })));
+    }
+
+    #[cfg(feature = "ttf")]
+    #[test]
+    fn reject_invalid_ttf() {
+        let result = Font::from_ttf(TEST_BDF.as_bytes(), &TtfOptions::new(16.0));
+        assert!(matches!(result, Err(FontError::UnsupportedFormat)));
+    }
+
+    #[cfg(feature = "fonts")]
+    #[test]
+    fn builtin_fonts_parse() {
+        for builtin in BuiltinFont::ALL.iter() {
+            let font = Font::builtin(*builtin);
+            assert!(font.contains('A'), "{:?} has no 'A'", builtin);
+        }
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(Font::from_bytes(b"hello").is_err());
+        let truncated = &TEST_BDF[..TEST_BDF.find("BITMAP").unwrap()];
+        assert!(Font::from_bytes(truncated.as_bytes()).is_err());
+        let huge = TEST_BDF.replacen("BBX 5 8 0 -1", "BBX 100000 100000 0 0", 1);
+        assert_ne!(huge, TEST_BDF);
+        assert!(Font::from_bytes(huge.as_bytes()).is_err());
+
+        let bbx = BoundingBox { width: 2, height: 1, x_offset: 0, y_offset: 0 };
+        assert!(Glyph::new(2, bbx, vec![255, 0]).is_ok());
+        assert!(Glyph::new(2, bbx, vec![255]).is_err());
+        let huge = BoundingBox { width: 100000, height: 100000, x_offset: 0, y_offset: 0 };
+        assert!(Glyph::new(2, huge, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! A small REST API for controlling a running matrix.
+//!
+//! | Request                 | Does                                                  |
+//! |-------------------------|-------------------------------------------------------|
+//! | `GET /brightness`       | `{"brightness": 80}`                                  |
+//! | `PUT /brightness?value=`| sets the brightness, 0 to 100                         |
+//! | `POST /text`            | shows the body as text; `font`, `color` (`ff8000`) and `speed` (pixels per second, 0 holds still) query parameters |
+//! | `POST /image`           | shows the uploaded PNG, JPEG, BMP, GIF or PPM file    |
+//! | `POST /clear`           | blanks the matrix                                     |
+//! | `GET /snapshot.png`     | what is on the matrix, `scale` and `round` optional   |
+//!
+//! Request threads only record what to show; one render thread owns the
+//! canvases and draws, so the matrix is only ever locked briefly.
+
+use super::animation::StopHandle;
+use super::canvas::{Canvas, Rect};
+use super::font::Font;
+use super::image::{DrawImageOptions, Image};
+use super::matrix::Matrix;
+use super::screenshot::{self, LedShape, ScreenshotOptions};
+use super::text::{self, FRAME_INTERVAL};
+
+use rgb::RGB8;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use tiny_http::{Header, Method, Request, Response};
+
+/// A matrix shared between the render thread and request threads.
+pub type SharedMatrix = Arc<Mutex<Matrix>>;
+
+const WORKERS: usize = 2;
+/// Uploads bigger than this are refused.
+const MAX_UPLOAD: u64 = 16 * 1024 * 1024;
+
+/*
+ * Requests
+ */
+
+/// Splits `path?a=1&b=2` into the path and its decoded parameters.
+pub(crate) fn parse_url(url: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let parameters = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    (path, parameters)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => out.push(b' '),
+            b'%' if index + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[index + 1..index + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        index += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses `rrggbb`, with or without a leading `#`.
+pub(crate) fn parse_color(text: &str) -> Option<RGB8> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |range: std::ops::Range<usize>| u8::from_str_radix(hex.get(range)?, 16).ok();
+    Some(RGB8::new(channel(0..2)?, channel(2..4)?, channel(4..6)?))
+}
+
+/*
+ * Content
+ */
+
+#[derive(Clone)]
+enum Content {
+    Blank,
+    Text { text: String, font: String, color: RGB8, speed: f32, started: Instant },
+    Image(Arc<Image>),
+}
+
+struct State {
+    matrix: SharedMatrix,
+    width: i32,
+    height: i32,
+    fonts: HashMap<String, Font>,
+    default_font: Option<String>,
+    /// What to show, and a counter bumped on every change.
+    content: Mutex<(Content, u64)>,
+}
+
+impl State {
+    fn show(&self, content: Content) {
+        let mut current = self.content.lock().unwrap();
+        *current = (content, current.1 + 1);
+    }
+
+    fn redraw(&self) {
+        self.content.lock().unwrap().1 += 1;
+    }
+}
+
+fn draw(canvas: &mut Canvas, content: &Content, fonts: &HashMap<String, Font>) {
+    canvas.clear();
+    let (width, height) = canvas.get_size();
+    match content {
+        Content::Blank => {}
+        Content::Image(image) => canvas.draw_image(image, &Rect::new(0, 0, width, height), &DrawImageOptions::default()),
+        Content::Text { text, font, color, speed, started } => {
+            let font = match fonts.get(font) {
+                Some(font) => font,
+                None => return,
+            };
+            let origin = text::marquee_origin(font, text, (width, height), *speed, started.elapsed());
+            canvas.draw_text(font, &origin, color, text, 0);
+        }
+    }
+}
+
+/// Draws whenever the content changes, and every frame while text scrolls.
+fn render(state: &State, stop: &StopHandle) {
+    let (mut displayed, mut offscreen) = {
+        let mut matrix = state.matrix.lock().unwrap();
+        (matrix.get_canvas(), matrix.create_offscreen_canvas())
+    };
+
+    let mut drawn = None;
+    while !stop.is_stopped() {
+        let (content, generation) = state.content.lock().unwrap().clone();
+        let animated = matches!(content, Content::Text { speed, .. } if speed > 0.0);
+        if animated || drawn != Some(generation) {
+            draw(&mut offscreen, &content, &state.fonts);
+            state.matrix.lock().unwrap().swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+            std::mem::swap(&mut offscreen, &mut displayed);
+            drawn = Some(generation);
+        }
+        thread::sleep(FRAME_INTERVAL);
+    }
+}
+
+/*
+ * Handlers
+ */
+
+fn json(body: String) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_string(body).with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+/// `text` as a quoted JSON string.
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn error(status: u16, message: &str) -> Response<io::Cursor<Vec<u8>>> {
+    json(format!("{{\"error\": {}}}", json_string(message))).with_status_code(status)
+}
+
+fn handle(state: &State, request: &mut Request) -> Response<io::Cursor<Vec<u8>>> {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let (path, parameters) = parse_url(&url);
+
+    match (method, path) {
+        (Method::Get, "/brightness") => {
+            let brightness = state.matrix.lock().unwrap().get_brightness();
+            json(format!("{{\"brightness\": {}}}", brightness))
+        }
+        (Method::Put, "/brightness") | (Method::Post, "/brightness") => {
+            match parameters.get("value").and_then(|value| value.parse::<u8>().ok()) {
+                Some(value) if value <= 100 => {
+                    state.matrix.lock().unwrap().set_brightness(value);
+                    // the C library applies brightness while drawing, so draw again
+                    state.redraw();
+                    json(format!("{{\"brightness\": {}}}", value))
+                }
+                _ => error(400, "value must be 0 to 100"),
+            }
+        }
+        (Method::Post, "/text") => {
+            let mut text = String::new();
+            if request.as_reader().take(MAX_UPLOAD).read_to_string(&mut text).is_err() {
+                return error(400, "text must be UTF-8");
+            }
+            let font = match parameters.get("font").or(state.default_font.as_ref()) {
+                Some(font) if state.fonts.contains_key(font) => font.clone(),
+                _ => return error(400, "unknown font"),
+            };
+            let color = match parameters.get("color") {
+                Some(color) => match parse_color(color) {
+                    Some(color) => color,
+                    None => return error(400, "color must look like ff8000"),
+                },
+                None => RGB8::new(255, 255, 255),
+            };
+            let speed = parameters.get("speed").and_then(|speed| speed.parse().ok()).unwrap_or(0.0);
+            state.show(Content::Text { text: text.trim_end().to_string(), font, color, speed, started: Instant::now() });
+            json("{}".to_string())
+        }
+        (Method::Post, "/image") => {
+            let mut data = Vec::new();
+            if let Err(err) = request.as_reader().take(MAX_UPLOAD).read_to_end(&mut data) {
+                return error(400, &err.to_string());
+            }
+            match Image::from_bytes(&data) {
+                Ok(image) => {
+                    state.show(Content::Image(Arc::new(image)));
+                    json("{}".to_string())
+                }
+                Err(err) => error(400, &err.to_string()),
+            }
+        }
+        (Method::Post, "/clear") => {
+            state.show(Content::Blank);
+            json("{}".to_string())
+        }
+        (Method::Get, "/snapshot.png") => {
+            let scale = parameters.get("scale").and_then(|scale| scale.parse().ok()).unwrap_or(1).clamp(1, 32);
+            let shape = if parameters.contains_key("round") { LedShape::Round } else { LedShape::Square };
+            let options = ScreenshotOptions::new(scale, shape);
+            let image = {
+                let matrix = state.matrix.lock().unwrap();
+                screenshot::render(matrix.front_pixels(), state.width, state.height, &options)
+            };
+            let mut png = Vec::new();
+            match screenshot::write_png(&image, &mut png) {
+                Ok(()) => Response::from_data(png).with_header(Header::from_bytes("Content-Type", "image/png").unwrap()),
+                Err(err) => error(500, &err.to_string()),
+            }
+        }
+        _ => error(404, "no such endpoint"),
+    }
+}
+
+/*
+ * Server
+ */
+
+pub struct HttpServer {
+    server: Arc<tiny_http::Server>,
+    stop: StopHandle,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    /// Serves the API on `address` and starts drawing on `matrix`. Text can
+    /// use any of `fonts` by name, the first being the default.
+    pub fn start<A: ToSocketAddrs>(address: A, matrix: SharedMatrix, fonts: Vec<(String, Font)>) -> io::Result<HttpServer> {
+        let server = tiny_http::Server::http(address).map_err(|err| io::Error::other(err.to_string()))?;
+        let server = Arc::new(server);
+        let stop = StopHandle::new();
+        let (width, height) = matrix.lock().unwrap().get_canvas().get_size();
+        let state = Arc::new(State {
+            matrix,
+            width,
+            height,
+            default_font: fonts.first().map(|(name, _)| name.clone()),
+            fonts: fonts.into_iter().collect(),
+            content: Mutex::new((Content::Blank, 0)),
+        });
+
+        let mut threads = Vec::new();
+        {
+            let (state, stop) = (state.clone(), stop.clone());
+            threads.push(thread::spawn(move || render(&state, &stop)));
+        }
+        for _ in 0..WORKERS {
+            let (state, stop, server) = (state.clone(), stop.clone(), server.clone());
+            threads.push(thread::spawn(move || {
+                while !stop.is_stopped() {
+                    if let Ok(Some(mut request)) = server.recv_timeout(FRAME_INTERVAL * 3) {
+                        let response = handle(&state, &mut request);
+                        let _ = request.respond(response);
+                    }
+                }
+            }));
+        }
+
+        Ok(HttpServer { server, stop, threads })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Stops serving and drawing, and waits for the threads to finish.
+    pub fn stop(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        self.stop.stop();
+        self.server.unblock();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::remote::{self, Update};
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    #[test]
+    fn parses_query_and_colors() {
+        let (path, parameters) = parse_url("/text?font=5x8&color=%23ff8000&speed=12.5&note=a+b");
+        assert_eq!(path, "/text");
+        assert_eq!(parameters["font"], "5x8");
+        assert_eq!(parameters["note"], "a b");
+        assert_eq!(parse_color(&parameters["color"]), Some(RGB8::new(255, 128, 0)));
+        assert_eq!(parse_url("/clear").1.len(), 0);
+        assert_eq!(parse_color("12345"), None);
+        assert_eq!(parse_color("gg0000"), None);
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a \"b\" c:\\d\n\u{1}"), "\"a \\\"b\\\" c:\\\\d\\n\\u0001\"");
+    }
+
+    /// Sends a bodyless request and returns the response.
+    fn request(address: SocketAddr, method_and_path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", method_and_path)
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn handles_requests() {
+        let mut panels = remote::Server::bind("127.0.0.1:0", 4, 2).unwrap();
+        let panels_address = panels.local_addr().unwrap();
+        let stop = StopHandle::new();
+        let serving_stop = stop.clone();
+        let serving = thread::spawn(move || {
+            while !serving_stop.is_stopped() {
+                if let Ok(Some(Update::Frame)) = panels.receive() {
+                    panels.acknowledge();
+                }
+            }
+        });
+
+        let matrix = Arc::new(Mutex::new(Matrix::connect(panels_address).unwrap()));
+        let server = HttpServer::start("127.0.0.1:0", matrix.clone(), Vec::new()).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let response = request(address, "PUT /brightness?value=40");
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("{\"brightness\": 40}"), "{}", response);
+        assert_eq!(matrix.lock().unwrap().get_brightness(), 40);
+        let response = request(address, "PUT /brightness?value=150");
+        assert!(response.starts_with("HTTP/1.1 400") && response.ends_with("{\"error\": \"value must be 0 to 100\"}"));
+        assert!(request(address, "GET /nothing").starts_with("HTTP/1.1 404"));
+
+        server.stop();
+        stop.stop();
+        serving.join().unwrap();
+    }
+}
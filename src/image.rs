@@ -0,0 +1,434 @@
+use super::canvas::Rect;
+
+use rgb::{RGB8, RGBA8};
+use std::fmt;
+use std::io;
+#[cfg(feature = "image")]
+use std::path::Path;
+
+/*
+ * Errors
+ */
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// The image file could not be read.
+    Io(io::Error),
+    /// The data is not an image in a supported format, or is corrupt.
+    Decode(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "could not read image: {}", err),
+            ImageError::Decode(message) => write!(f, "could not decode image: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+/*
+ * Drawing Options
+ */
+
+/// How an image is sized to the rectangle it is drawn into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scaling {
+    /// Drawn at its own size from the top left corner, cropped to the rectangle.
+    None,
+    /// Scaled to fit entirely inside, keeping its aspect ratio, centered.
+    Fit,
+    /// Scaled to cover the whole rectangle, keeping its aspect ratio,
+    /// centered and cropped.
+    Fill,
+    /// Scaled to exactly the rectangle, distorting if need be.
+    Stretch,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resampling {
+    /// Fast and blocky; best for pixel art.
+    Nearest,
+    /// Smooth when enlarging.
+    Bilinear,
+    /// Averages every source pixel under a target pixel; best for shrinking
+    /// photos down to panel size.
+    Area,
+}
+
+/// What happens to pixels that are not fully opaque.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    /// Pixels with less alpha than this are left untouched on the canvas,
+    /// the rest are drawn opaque.
+    Threshold(u8),
+    /// Pixels are blended onto this color.
+    Matte(RGB8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DrawImageOptions {
+    pub scaling: Scaling,
+    pub resampling: Resampling,
+    pub transparency: Transparency,
+}
+
+impl DrawImageOptions {
+    pub fn new(scaling: Scaling) -> DrawImageOptions {
+        DrawImageOptions {
+            scaling,
+            resampling: Resampling::Area,
+            transparency: Transparency::Threshold(128),
+        }
+    }
+}
+
+impl Default for DrawImageOptions {
+    fn default() -> DrawImageOptions {
+        DrawImageOptions::new(Scaling::Fit)
+    }
+}
+
+impl Transparency {
+    /// The color to draw for `pixel`, or `None` to leave the canvas alone.
+    pub(crate) fn apply(&self, pixel: RGBA8) -> Option<RGB8> {
+        match *self {
+            Transparency::Threshold(threshold) => {
+                if pixel.a >= threshold && pixel.a > 0 {
+                    Some(pixel.rgb())
+                } else {
+                    None
+                }
+            }
+            Transparency::Matte(matte) => {
+                let blend = |fg: u8, bg: u8| ((fg as u32 * pixel.a as u32 + bg as u32 * (255 - pixel.a as u32)) / 255) as u8;
+                Some(RGB8::new(blend(pixel.r, matte.r), blend(pixel.g, matte.g), blend(pixel.b, matte.b)))
+            }
+        }
+    }
+}
+
+/*
+ * Image
+ */
+
+/// An RGBA picture held in memory, ready to be drawn with `Canvas::draw_image`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    width: i32,
+    height: i32,
+    pixels: Vec<RGBA8>,
+}
+
+impl Image {
+    /// Makes an image from pixels in rows top to bottom. Panics if the
+    /// number of pixels doesn't match the size.
+    pub fn new(width: i32, height: i32, pixels: Vec<RGBA8>) -> Image {
+        assert_eq!(pixels.len(), (width.max(0) * height.max(0)) as usize, "pixel count doesn't match the size");
+        Image { width, height, pixels }
+    }
+
+    /// Makes an opaque image from packed RGB24 data, as produced by video
+    /// decoders and PPM files. Fails if there isn't exactly one RGB triple
+    /// per pixel.
+    pub fn from_rgb24(width: i32, height: i32, data: &[u8]) -> Result<Image, ImageError> {
+        let expected = (width.max(0) as usize).checked_mul(height.max(0) as usize).and_then(|count| count.checked_mul(3));
+        if expected != Some(data.len()) {
+            return Err(ImageError::Decode("RGB24 data doesn't match the size".to_string()));
+        }
+        let pixels = data.chunks_exact(3).map(|px| RGBA8::new(px[0], px[1], px[2], 255)).collect();
+        Ok(Image::new(width, height, pixels))
+    }
+
+    /// Loads a PNG, JPEG, BMP, GIF (first frame) or PPM/PNM file.
+    #[cfg(feature = "image")]
+    pub fn open(path: &Path) -> Result<Image, ImageError> {
+        Image::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Decodes an image held in memory, guessing the format from its contents.
+    #[cfg(feature = "image")]
+    pub fn from_bytes(data: &[u8]) -> Result<Image, ImageError> {
+        let decoded = ::image::load_from_memory(data).map_err(|err| ImageError::Decode(err.to_string()))?;
+        Ok(Image::from_rgba_image(decoded.to_rgba8()))
+    }
+
+    #[cfg(feature = "image")]
+    pub(crate) fn from_rgba_image(decoded: ::image::RgbaImage) -> Image {
+        let (width, height) = decoded.dimensions();
+        let pixels = decoded.pixels().map(|px| RGBA8::new(px[0], px[1], px[2], px[3])).collect();
+        Image::new(width as i32, height as i32, pixels)
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[RGBA8] {
+        &self.pixels
+    }
+
+    /// The pixel at (`x`, `y`), clamped to the edges of the image. An empty
+    /// image is transparent everywhere.
+    pub fn pixel(&self, x: i32, y: i32) -> RGBA8 {
+        if self.pixels.is_empty() {
+            return RGBA8::default();
+        }
+        let x = x.max(0).min(self.width - 1);
+        let y = y.max(0).min(self.height - 1);
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Resamples the image to a new size.
+    pub fn resize(&self, width: i32, height: i32, resampling: Resampling) -> Image {
+        self.resize_window(width, height, &Rect::new(0, 0, width, height), resampling)
+    }
+
+    /// The `window` part of the image resized to `width` by `height`, with
+    /// only that part resampled. `window` is in resized coordinates.
+    pub(crate) fn resize_window(&self, width: i32, height: i32, window: &Rect, resampling: Resampling) -> Image {
+        let (window_width, window_height) = (window.width.max(0), window.height.max(0));
+        if width <= 0 || height <= 0 || self.width <= 0 || self.height <= 0 {
+            return Image::new(window_width, window_height, vec![RGBA8::default(); (window_width * window_height) as usize]);
+        }
+        if width == self.width && height == self.height && *window == Rect::new(0, 0, width, height) {
+            return self.clone();
+        }
+
+        let scale_x = self.width as f32 / width as f32;
+        let scale_y = self.height as f32 / height as f32;
+        let mut pixels = Vec::with_capacity((window_width * window_height) as usize);
+        for y in window.y..window.y + window_height {
+            for x in window.x..window.x + window_width {
+                pixels.push(match resampling {
+                    Resampling::Nearest => self.pixel(
+                        ((x as f32 + 0.5) * scale_x) as i32,
+                        ((y as f32 + 0.5) * scale_y) as i32,
+                    ),
+                    Resampling::Bilinear => self.bilinear(
+                        (x as f32 + 0.5) * scale_x - 0.5,
+                        (y as f32 + 0.5) * scale_y - 0.5,
+                    ),
+                    Resampling::Area => self.area(
+                        x as f32 * scale_x,
+                        y as f32 * scale_y,
+                        scale_x,
+                        scale_y,
+                    ),
+                });
+            }
+        }
+
+        Image::new(window_width, window_height, pixels)
+    }
+
+    fn bilinear(&self, sx: f32, sy: f32) -> RGBA8 {
+        let (x0, y0) = (sx.floor(), sy.floor());
+        let (fx, fy) = (sx - x0, sy - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+
+        let mut samples = Accumulator::default();
+        samples.add(self.pixel(x0, y0), (1.0 - fx) * (1.0 - fy));
+        samples.add(self.pixel(x0 + 1, y0), fx * (1.0 - fy));
+        samples.add(self.pixel(x0, y0 + 1), (1.0 - fx) * fy);
+        samples.add(self.pixel(x0 + 1, y0 + 1), fx * fy);
+        samples.result()
+    }
+
+    /// Box filter over the source rectangle starting at (`sx`, `sy`).
+    fn area(&self, sx: f32, sy: f32, width: f32, height: f32) -> RGBA8 {
+        let mut samples = Accumulator::default();
+        let mut y = sy.floor();
+        while y < sy + height {
+            let weight_y = (y + 1.0).min(sy + height) - y.max(sy);
+            let mut x = sx.floor();
+            while x < sx + width {
+                let weight_x = (x + 1.0).min(sx + width) - x.max(sx);
+                samples.add(self.pixel(x as i32, y as i32), weight_x * weight_y);
+                x += 1.0;
+            }
+            y += 1.0;
+        }
+        samples.result()
+    }
+
+    /// Scales the image as `options` says and places it on a transparent
+    /// image of the given size, e.g. to pre-render it for a whole canvas.
+    pub fn render_into(&self, width: i32, height: i32, options: &DrawImageOptions) -> Image {
+        let target = Rect::new(0, 0, width, height);
+        let placement = self.placement(&target, options.scaling);
+        let mut pixels = vec![RGBA8::default(); (width.max(0) * height.max(0)) as usize];
+        let visible = match placement.intersection(&target) {
+            Some(visible) => visible,
+            None => return Image::new(width.max(0), height.max(0), pixels),
+        };
+        let window = Rect::new(visible.x - placement.x, visible.y - placement.y, visible.width, visible.height);
+        let scaled = self.resize_window(placement.width, placement.height, &window, options.resampling);
+
+        for sy in 0..scaled.height {
+            for sx in 0..scaled.width {
+                let (x, y) = (visible.x + sx, visible.y + sy);
+                pixels[(y * width + x) as usize] = scaled.pixels[(sy * scaled.width + sx) as usize];
+            }
+        }
+
+        Image::new(width, height, pixels)
+    }
+
+    /// Where an image of this size lands inside `target` for `scaling`. The
+    /// result may stick out of `target`, in which case it is cropped.
+    pub(crate) fn placement(&self, target: &Rect, scaling: Scaling) -> Rect {
+        let (width, height) = match scaling {
+            Scaling::None => return Rect::new(target.x, target.y, self.width, self.height),
+            Scaling::Stretch => return *target,
+            Scaling::Fit | Scaling::Fill => {
+                let scale_x = target.width as f32 / self.width as f32;
+                let scale_y = target.height as f32 / self.height as f32;
+                let scale = if scaling == Scaling::Fit { scale_x.min(scale_y) } else { scale_x.max(scale_y) };
+                (
+                    ((self.width as f32 * scale).round() as i32).max(1),
+                    ((self.height as f32 * scale).round() as i32).max(1),
+                )
+            }
+        };
+
+        Rect::new(
+            target.x + (target.width - width) / 2,
+            target.y + (target.height - height) / 2,
+            width,
+            height,
+        )
+    }
+}
+
+/// Weighted average of pixels with premultiplied alpha, so transparent
+/// pixels don't bleed their color into their neighbours.
+#[derive(Default)]
+struct Accumulator {
+    r: f32,
+    g: f32,
+    b: f32,
+    a: f32,
+    weight: f32,
+}
+
+impl Accumulator {
+    fn add(&mut self, pixel: RGBA8, weight: f32) {
+        let alpha = pixel.a as f32 * weight;
+        self.r += pixel.r as f32 * alpha;
+        self.g += pixel.g as f32 * alpha;
+        self.b += pixel.b as f32 * alpha;
+        self.a += alpha;
+        self.weight += weight;
+    }
+
+    fn result(&self) -> RGBA8 {
+        if self.a <= 0.0 || self.weight <= 0.0 {
+            return RGBA8::default();
+        }
+        RGBA8::new(
+            (self.r / self.a).round() as u8,
+            (self.g / self.a).round() as u8,
+            (self.b / self.a).round() as u8,
+            (self.a / self.weight).round() as u8,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> Image {
+        let black = RGBA8::new(0, 0, 0, 255);
+        let white = RGBA8::new(255, 255, 255, 255);
+        Image::new(2, 2, vec![black, white, white, black])
+    }
+
+    #[test]
+    fn resize() {
+        let image = checkerboard();
+        let big = image.resize(4, 4, Resampling::Nearest);
+        assert_eq!(big.pixel(1, 1), image.pixel(0, 0));
+        assert_eq!(big.pixel(2, 1), image.pixel(1, 0));
+
+        let small = image.resize(1, 1, Resampling::Area);
+        assert_eq!(small.pixel(0, 0), RGBA8::new(128, 128, 128, 255));
+
+        let smooth = image.resize(4, 1, Resampling::Bilinear);
+        assert!(smooth.pixel(1, 0).r > 0 && smooth.pixel(1, 0).r < 255);
+    }
+
+    #[test]
+    fn transparent_pixels_keep_their_neighbours_color() {
+        let red = RGBA8::new(255, 0, 0, 255);
+        let image = Image::new(2, 1, vec![red, RGBA8::new(0, 255, 0, 0)]);
+        let pixel = image.resize(1, 1, Resampling::Area).pixel(0, 0);
+        assert_eq!(pixel, RGBA8::new(255, 0, 0, 128));
+    }
+
+    #[test]
+    fn placement() {
+        let image = Image::new(4, 2, vec![RGBA8::default(); 8]);
+        let target = Rect::new(0, 0, 8, 8);
+        assert_eq!(image.placement(&target, Scaling::None), Rect::new(0, 0, 4, 2));
+        assert_eq!(image.placement(&target, Scaling::Fit), Rect::new(0, 2, 8, 4));
+        assert_eq!(image.placement(&target, Scaling::Fill), Rect::new(-4, 0, 16, 8));
+        assert_eq!(image.placement(&target, Scaling::Stretch), target);
+    }
+
+    #[test]
+    fn fill_resamples_only_the_visible_part() {
+        let image = Image::new(1, 10_000, (0..10_000).map(|i| RGBA8::new((i % 256) as u8, 0, 0, 255)).collect());
+        let target = Rect::new(0, 0, 64, 32);
+        let placement = image.placement(&target, Scaling::Fill);
+        assert_eq!((placement.width, placement.height), (64, 640_000));
+
+        let window = Rect::new(0, -placement.y, 64, 32);
+        let visible = image.resize_window(placement.width, placement.height, &window, Resampling::Nearest);
+        assert_eq!((visible.width(), visible.height()), (64, 32));
+        let full_row = image.pixel(0, -placement.y / 64);
+        assert_eq!(visible.pixel(5, 0), full_row);
+
+        let rendered = image.render_into(64, 32, &DrawImageOptions::new(Scaling::Fill));
+        assert_eq!(rendered.pixels(), visible.pixels());
+    }
+
+    #[test]
+    fn transparency() {
+        let pixel = RGBA8::new(200, 100, 0, 51);
+        assert_eq!(Transparency::Threshold(128).apply(pixel), None);
+        assert_eq!(Transparency::Threshold(50).apply(pixel), Some(RGB8::new(200, 100, 0)));
+        assert_eq!(Transparency::Matte(RGB8::new(0, 0, 255)).apply(pixel), Some(RGB8::new(40, 20, 204)));
+    }
+
+    #[test]
+    fn from_rgb24() {
+        let image = Image::from_rgb24(2, 1, &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(image.pixel(1, 0), RGBA8::new(4, 5, 6, 255));
+        assert!(Image::from_rgb24(2, 1, &[1, 2, 3, 4]).is_err());
+        assert!(Image::from_rgb24(1, 1, &[1, 2, 3, 4, 5, 6]).is_err());
+
+        let empty = Image::from_rgb24(0, 3, &[]).unwrap();
+        assert_eq!(empty.pixel(0, 0), RGBA8::default());
+    }
+}
@@ -2,7 +2,21 @@
 pub const ARGV_MAX_SIZE: usize = 64;
 
 pub mod matrix;
+pub mod animation;
 pub mod canvas;
+pub mod font;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod image;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod net;
+#[cfg(feature = "preview")]
+pub mod preview;
+pub mod screenshot;
+pub mod stream;
+pub mod text;
+pub mod video;
 
 // internally public
 pub(crate) mod c_api;
@@ -3,9 +3,15 @@ use super::c_datatypes;
 use super::ARGV_MAX_SIZE;
 use super::helper_functions;
 use super::canvas;
+use super::net::remote;
+#[cfg(feature = "preview")]
+use super::preview;
 
 use std::ffi::CString;
+use std::io;
+use std::net::ToSocketAddrs;
 use libc::{c_int, c_char};
+use rgb::RGB8;
 
 pub enum HardwareMapping {
     Regular = 0,
@@ -155,6 +161,12 @@ impl LEDMatrixOptions {
 pub struct Matrix {
     matrix: *mut c_datatypes::RGBLedMatrix,
     pub options: LEDMatrixOptions,
+    // what the last swap put on the matrix, handed to `get_canvas`
+    front: Vec<RGB8>,
+    // frames go here instead of to the C library if set
+    remote: Option<remote::Connection>,
+    #[cfg(feature = "preview")]
+    preview: Option<preview::Publisher>,
 }
 
 impl Matrix {
@@ -185,7 +197,11 @@ impl Matrix {
 
             Matrix {
                 matrix: m,
-                options: updated_options
+                options: updated_options,
+                front: Matrix::blank_front(m),
+                remote: None,
+                #[cfg(feature = "preview")]
+                preview: None,
             }
         }
     }
@@ -199,25 +215,77 @@ impl Matrix {
                 chained, parallel,
                 100
             );
-            Matrix { matrix: m, options: options }
+            Matrix {
+                matrix: m,
+                options: options,
+                front: Matrix::blank_front(m),
+                remote: None,
+                #[cfg(feature = "preview")]
+                preview: None,
+            }
         }
     }
 
+    /// A matrix attached to another machine, shown by the `net::remote`
+    /// server at `address`. It takes the server's size and brightness, and
+    /// the rest of the API works the same as on the hardware.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Matrix> {
+        let connection = remote::Connection::open(address)?;
+        let hello = connection.hello();
+        let options = LEDMatrixOptions::new(
+            HardwareMapping::Regular,
+            hello.height, hello.width,
+            1, 1,
+            hello.brightness
+        );
+        Ok(Matrix {
+            matrix: std::ptr::null_mut(),
+            options,
+            front: vec![RGB8::default(); hello.pixel_count()],
+            remote: Some(connection),
+            #[cfg(feature = "preview")]
+            preview: None,
+        })
+    }
+
+    /// The C library starts with cleared buffers, so this is what's shown
+    /// before the first swap.
+    fn blank_front(matrix: *mut c_datatypes::RGBLedMatrix) -> Vec<RGB8> {
+        let (width, height) = unsafe { canvas::Canvas::new(c_api::led_matrix_get_canvas(matrix)).get_size() };
+        vec![RGB8::default(); (width * height).max(0) as usize]
+    }
+
     pub fn get_brightness(&mut self) -> u8 {
+        if self.remote.is_some() {
+            return self.options.brightness;
+        }
         unsafe { c_api::led_matrix_get_brightness(self.matrix) }
     }
 
     pub fn set_brightness(&mut self, brightness: u8) {
+        if let Some(remote) = &mut self.remote {
+            self.options.brightness = brightness;
+            remote.send_brightness(brightness);
+            return;
+        }
         unsafe {
             c_api::led_matrix_set_brightness(self.matrix, brightness);
         }
     }
 
     pub fn get_canvas(&mut self) -> canvas::Canvas {
-        unsafe { canvas::Canvas::new(c_api::led_matrix_get_canvas(self.matrix)) }
+        if let Some(remote) = &self.remote {
+            let hello = remote.hello();
+            return canvas::Canvas::detached(hello.width, hello.height, &self.front);
+        }
+        unsafe { canvas::Canvas::with_pixels(c_api::led_matrix_get_canvas(self.matrix), &self.front) }
     }
 
     pub fn create_offscreen_canvas(&mut self) -> canvas::Canvas {
+        if let Some(remote) = &self.remote {
+            let hello = remote.hello();
+            return canvas::Canvas::detached(hello.width, hello.height, &[]);
+        }
         unsafe { canvas::Canvas::new(c_api::led_matrix_create_offscreen_canvas(self.matrix)) }
     }
 
@@ -226,15 +294,57 @@ impl Matrix {
         canvas_to_draw: &mut canvas::Canvas,
         new_offscreen_canvas: &mut canvas::Canvas,
     ) {
-        unsafe {
-            new_offscreen_canvas.canvas =
-                c_api::led_matrix_swap_on_vsync(self.matrix, canvas_to_draw.canvas);
+        let held_before = new_offscreen_canvas.canvas;
+        match &mut self.remote {
+            // waits until the server has shown it
+            Some(remote) => remote.send_frame(&canvas_to_draw.pixels),
+            None => unsafe {
+                new_offscreen_canvas.canvas =
+                    c_api::led_matrix_swap_on_vsync(self.matrix, canvas_to_draw.canvas);
+            },
+        }
+        // the buffer handed back is the one that was on the matrix until now.
+        // If the canvas already held it, its shadow is up to date, including
+        // anything drawn on it while it was shown; otherwise it gets what the
+        // last swap put up.
+        let previous = std::mem::replace(&mut self.front, canvas_to_draw.pixels.clone());
+        let shown_before = &mut new_offscreen_canvas.pixels;
+        if new_offscreen_canvas.canvas == held_before && shown_before.len() == previous.len() {
+            // keep it
+        } else if previous.len() == shown_before.len() {
+            *shown_before = previous;
+        } else {
+            shown_before.iter_mut().for_each(|pixel| *pixel = RGB8::default());
         }
+
+        #[cfg(feature = "preview")]
+        if let Some(preview) = &self.preview {
+            let (width, height) = canvas_to_draw.get_size();
+            preview.publish(&self.front, width, height);
+        }
+    }
+
+    /// The pixels shown since the last `swap_canvas_on_vsync`, as drawn.
+    pub fn front_pixels(&self) -> &[RGB8] {
+        &self.front
+    }
+
+    /// Offers every frame swapped in to `publisher`, or stops with `None`.
+    #[cfg(feature = "preview")]
+    pub fn set_preview(&mut self, publisher: Option<preview::Publisher>) {
+        self.preview = publisher;
     }
 }
 
+// The C library has no thread affinity, so a matrix may move to another
+// thread; sharing it still needs a Mutex.
+unsafe impl Send for Matrix {}
+
 impl Drop for Matrix {
     fn drop(&mut self) {
+        if self.remote.is_some() {
+            return;
+        }
         unsafe {
             c_api::led_matrix_delete(self.matrix);
         }
@@ -0,0 +1,596 @@
+//! Remote control over MQTT.
+//!
+//! `Controller` drives a matrix from commands published under a topic
+//! prefix, and publishes retained status topics next to them:
+//!
+//! | Topic                        | Payload                               |
+//! |------------------------------|---------------------------------------|
+//! | `<prefix>/brightness/set`    | `0` to `100`                          |
+//! | `<prefix>/text/set`          | text to show, scrolling if too wide   |
+//! | `<prefix>/scene/set`         | the name of a scene added with `add_scene` |
+//! | `<prefix>/power/set`         | `on` or `off`                         |
+//! | `<prefix>/status/brightness` | `0` to `100`                          |
+//! | `<prefix>/status/scene`      | the scene's name, `text` or `none`    |
+//! | `<prefix>/status/power`      | `on` or `off`                         |
+//! | `<prefix>/status/frame_rate` | frames drawn per second, at most about 30; not the panels' refresh rate |
+//! | `<prefix>/status/uptime`     | seconds since the controller started  |
+//!
+//! `Client` is the small MQTT 3.1.1 client underneath: QoS 0 only, which
+//! is all fire-and-forget commands and regularly refreshed status need.
+
+use super::animation::StopHandle;
+use super::canvas::Canvas;
+use super::font::Font;
+use super::matrix::Matrix;
+use super::text::{self, FRAME_INTERVAL};
+
+use rgb::RGB8;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 1883;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+const FLAG_RETAIN: u8 = 0x01;
+const CLEAN_SESSION: u8 = 0x02;
+const HAS_PASSWORD: u8 = 0x40;
+const HAS_USERNAME: u8 = 0x80;
+
+/// How long the broker gets to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How fast text too wide for the matrix scrolls, in pixels per second.
+const SCROLL_SPEED: f32 = 20.0;
+/// Refresh rates are averaged over at least this long.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/*
+ * Packets
+ */
+
+fn push_string(out: &mut Vec<u8>, text: &[u8]) {
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text);
+}
+
+/// A packet: its type and flags, the variable length and `body`.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        out.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// Finds the first whole packet in `data`, returning its first byte, where
+/// its body starts and where it ends.
+fn split_packet(data: &[u8]) -> Result<Option<(u8, usize, usize)>, &'static str> {
+    let mut length = 0;
+    for index in 1..data.len().min(5) {
+        length += (data[index] as usize & 0x7f) << (7 * (index - 1));
+        if data[index] & 0x80 == 0 {
+            let end = index + 1 + length;
+            return Ok(if end <= data.len() { Some((data[0], index + 1, end)) } else { None });
+        }
+    }
+    if data.len() >= 5 {
+        return Err("malformed packet length");
+    }
+    Ok(None)
+}
+
+/// A message received on a subscribed topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl Publish {
+    fn parse(flags: u8, body: &[u8]) -> Result<Publish, &'static str> {
+        if body.len() < 2 {
+            return Err("truncated publish");
+        }
+        let topic_end = 2 + u16::from_be_bytes([body[0], body[1]]) as usize;
+        // QoS 1 and 2 put a packet id after the topic
+        let payload_start = if flags & 0x06 != 0 { topic_end + 2 } else { topic_end };
+        if payload_start > body.len() {
+            return Err("truncated publish");
+        }
+        Ok(Publish {
+            topic: String::from_utf8_lossy(&body[2..topic_end]).into_owned(),
+            payload: body[payload_start..].to_vec(),
+            retain: flags & FLAG_RETAIN != 0,
+        })
+    }
+}
+
+/*
+ * Client
+ */
+
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub keep_alive: Duration,
+    /// A username and password, if the broker wants them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl ConnectOptions {
+    pub fn new(client_id: &str) -> ConnectOptions {
+        ConnectOptions { client_id: client_id.to_string(), keep_alive: Duration::from_secs(30), credentials: None }
+    }
+}
+
+pub struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    keep_alive: Duration,
+    last_sent: Instant,
+    next_packet_id: u16,
+}
+
+impl Client {
+    /// Connects to the broker at `address`, usually `(host, DEFAULT_PORT)`,
+    /// with a clean session.
+    pub fn connect<A: ToSocketAddrs>(address: A, options: &ConnectOptions) -> io::Result<Client> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let mut client = Client {
+            stream,
+            buffer: Vec::new(),
+            keep_alive: options.keep_alive,
+            last_sent: Instant::now(),
+            next_packet_id: 1,
+        };
+
+        let mut body = Vec::new();
+        push_string(&mut body, b"MQTT");
+        body.push(4);
+        let mut flags = CLEAN_SESSION;
+        if options.credentials.is_some() {
+            flags |= HAS_USERNAME | HAS_PASSWORD;
+        }
+        body.push(flags);
+        body.extend_from_slice(&(options.keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+        push_string(&mut body, options.client_id.as_bytes());
+        if let Some((username, password)) = &options.credentials {
+            push_string(&mut body, username.as_bytes());
+            push_string(&mut body, password.as_bytes());
+        }
+        client.send(&packet(CONNECT, &body))?;
+
+        let (kind, body) = client.read_packet(CONNECT_TIMEOUT)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no answer from the broker"))?;
+        match (kind & 0xf0, body.get(1)) {
+            (CONNACK, Some(0)) => Ok(client),
+            (CONNACK, Some(code)) => Err(io::Error::other(format!("the broker refused the connection ({})", code))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected a CONNACK")),
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Subscribes to `topics`, which may use the `+` and `#` wildcards.
+    pub fn subscribe(&mut self, topics: &[&str]) -> io::Result<()> {
+        let mut body = self.next_packet_id.to_be_bytes().to_vec();
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        for topic in topics {
+            push_string(&mut body, topic.as_bytes());
+            body.push(0);
+        }
+        self.send(&packet(SUBSCRIBE, &body))
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        push_string(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+        self.send(&packet(if retain { PUBLISH | FLAG_RETAIN } else { PUBLISH }, &body))
+    }
+
+    /// Waits up to `timeout` for the next packet, returning its first byte
+    /// and body.
+    fn read_packet(&mut self, timeout: Duration) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some((kind, start, end)) =
+                split_packet(&self.buffer).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            {
+                let body = self.buffer[start..end].to_vec();
+                self.buffer.drain(..end);
+                return Ok(Some((kind, body)));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.stream.set_read_timeout(Some((deadline - now).max(Duration::from_millis(1))))?;
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the broker closed the connection")),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for a message on a subscribed topic, pinging
+    /// the broker as the keep alive asks.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Option<Publish>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.keep_alive > Duration::from_secs(0) && self.last_sent.elapsed() >= self.keep_alive / 2 {
+                self.send(&packet(PINGREQ, &[]))?;
+            }
+            let (kind, body) = match self.read_packet(deadline.saturating_duration_since(Instant::now()))? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            // acknowledgements and ping responses need nothing from us
+            if kind & 0xf0 != PUBLISH {
+                continue;
+            }
+            let publish = Publish::parse(kind, &body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if kind & 0x06 == 0x02 {
+                let id_at = body.len() - publish.payload.len() - 2;
+                self.send(&packet(PUBACK, &body[id_at..id_at + 2]))?;
+            }
+            return Ok(Some(publish));
+        }
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))
+    }
+}
+
+/*
+ * Controller
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Brightness(u8),
+    Text(String),
+    Scene(String),
+    Power(bool),
+}
+
+impl Command {
+    /// Parses the payload sent to `<prefix>/<name>/set`.
+    pub fn parse(name: &str, payload: &[u8]) -> Result<Command, &'static str> {
+        let text = std::str::from_utf8(payload).map_err(|_| "payload isn't UTF-8")?;
+        match name {
+            "brightness" => match text.trim().parse() {
+                Ok(value) if value <= 100 => Ok(Command::Brightness(value)),
+                _ => Err("brightness must be 0 to 100"),
+            },
+            "text" => Ok(Command::Text(text.to_string())),
+            "scene" => Ok(Command::Scene(text.trim().to_string())),
+            "power" => match text.trim().to_ascii_lowercase().as_str() {
+                "on" | "1" | "true" => Ok(Command::Power(true)),
+                "off" | "0" | "false" => Ok(Command::Power(false)),
+                _ => Err("power must be on or off"),
+            },
+            _ => Err("unknown command"),
+        }
+    }
+}
+
+/// Something the controller can show, drawn afresh every frame.
+pub trait Scene: Send {
+    /// Draws onto a cleared `canvas`, `elapsed` after the scene was chosen.
+    fn draw(&mut self, canvas: &mut Canvas, elapsed: Duration);
+}
+
+impl<F: FnMut(&mut Canvas, Duration) + Send> Scene for F {
+    fn draw(&mut self, canvas: &mut Canvas, elapsed: Duration) {
+        self(canvas, elapsed)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub brightness: u8,
+    /// The scene's name, `text` or `none`.
+    pub scene: String,
+    pub power: bool,
+    /// Frames the controller drew in the last second. The C library
+    /// refreshes the panels far more often than that.
+    pub frame_rate: f32,
+    pub uptime: Duration,
+}
+
+impl Status {
+    /// The status topics under `prefix` and their payloads.
+    pub fn messages(&self, prefix: &str) -> Vec<(String, String)> {
+        vec![
+            (format!("{}/status/brightness", prefix), self.brightness.to_string()),
+            (format!("{}/status/scene", prefix), self.scene.clone()),
+            (format!("{}/status/power", prefix), if self.power { "on" } else { "off" }.to_string()),
+            (format!("{}/status/frame_rate", prefix), format!("{:.1}", self.frame_rate)),
+            (format!("{}/status/uptime", prefix), self.uptime.as_secs().to_string()),
+        ]
+    }
+}
+
+enum Showing {
+    Nothing,
+    Text(String),
+    Scene(String),
+}
+
+pub struct Controller {
+    client: Client,
+    prefix: String,
+    font: Font,
+    scenes: HashMap<String, Box<dyn Scene>>,
+    pub text_color: RGB8,
+    /// How often status is published; it also goes out after every command.
+    pub status_interval: Duration,
+    showing: Showing,
+    shown_since: Instant,
+    power: bool,
+    brightness: u8,
+    started: Instant,
+}
+
+impl Controller {
+    /// Subscribes `client` to the command topics under `prefix`, e.g.
+    /// `building/lobby/sign`. Text is drawn in `font`.
+    pub fn new(mut client: Client, prefix: &str, font: Font) -> io::Result<Controller> {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        client.subscribe(&[&format!("{}/+/set", prefix)])?;
+        Ok(Controller {
+            client,
+            prefix,
+            font,
+            scenes: HashMap::new(),
+            text_color: RGB8::new(255, 255, 255),
+            status_interval: Duration::from_secs(30),
+            showing: Showing::Nothing,
+            shown_since: Instant::now(),
+            power: true,
+            brightness: 100,
+            started: Instant::now(),
+        })
+    }
+
+    /// Makes `scene` selectable as `name`.
+    pub fn add_scene<S: Scene + 'static>(&mut self, name: &str, scene: S) {
+        self.scenes.insert(name.to_string(), Box::new(scene));
+    }
+
+    /// The command in `message`, if it was sent to a command topic.
+    pub fn command_for(&self, message: &Publish) -> Option<Result<Command, &'static str>> {
+        let name = message
+            .topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?;
+        Some(Command::parse(name, &message.payload))
+    }
+
+    pub fn apply(&mut self, command: Command) -> Result<(), &'static str> {
+        match command {
+            Command::Brightness(brightness) => self.brightness = brightness,
+            Command::Power(power) => self.power = power,
+            Command::Text(text) => self.show(Showing::Text(text)),
+            Command::Scene(name) => {
+                if !self.scenes.contains_key(&name) {
+                    return Err("unknown scene");
+                }
+                self.show(Showing::Scene(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn show(&mut self, showing: Showing) {
+        self.showing = showing;
+        self.shown_since = Instant::now();
+    }
+
+    pub fn status(&self, frame_rate: f32) -> Status {
+        let scene = match &self.showing {
+            Showing::Nothing => "none",
+            Showing::Text(_) => "text",
+            Showing::Scene(name) => name,
+        };
+        Status {
+            brightness: self.brightness,
+            scene: scene.to_string(),
+            power: self.power,
+            frame_rate,
+            uptime: self.started.elapsed(),
+        }
+    }
+
+    fn draw(&mut self, canvas: &mut Canvas) {
+        canvas.clear();
+        if !self.power {
+            return;
+        }
+        let elapsed = self.shown_since.elapsed();
+        match &self.showing {
+            Showing::Nothing => {}
+            Showing::Text(text) => {
+                let size = canvas.get_size();
+                let speed = if self.font.measure(text, 0).width > size.0 { SCROLL_SPEED } else { 0.0 };
+                let origin = text::marquee_origin(&self.font, text, size, speed, elapsed);
+                canvas.draw_text(&self.font, &origin, &self.text_color, text, 0);
+            }
+            Showing::Scene(name) => {
+                if let Some(scene) = self.scenes.get_mut(name) {
+                    scene.draw(canvas, elapsed);
+                }
+            }
+        }
+    }
+
+    fn publish_status(&mut self, frame_rate: f32) -> io::Result<()> {
+        for (topic, payload) in self.status(frame_rate).messages(&self.prefix) {
+            self.client.publish(&topic, payload.as_bytes(), true)?;
+        }
+        Ok(())
+    }
+
+    /// Follows commands and draws on `matrix` until `stop` is used, then
+    /// disconnects.
+    pub fn run(mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        self.brightness = matrix.get_brightness();
+        let mut displayed = matrix.get_canvas();
+        let mut offscreen = matrix.create_offscreen_canvas();
+
+        let (mut frames, mut counted_since, mut frame_rate) = (0, Instant::now(), 0.0);
+        let mut status_due = Instant::now();
+        while !stop.is_stopped() {
+            let deadline = Instant::now() + FRAME_INTERVAL;
+            while let Some(message) = self.client.poll(deadline.saturating_duration_since(Instant::now()))? {
+                // bad commands are dropped, there is nobody to tell
+                if let Some(Ok(command)) = self.command_for(&message) {
+                    if self.apply(command).is_ok() {
+                        status_due = Instant::now();
+                    }
+                }
+            }
+
+            if matrix.get_brightness() != self.brightness {
+                matrix.set_brightness(self.brightness);
+            }
+            self.draw(&mut offscreen);
+            matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+            std::mem::swap(&mut offscreen, &mut displayed);
+            frames += 1;
+
+            let now = Instant::now();
+            if now - counted_since >= RATE_WINDOW {
+                frame_rate = frames as f32 / (now - counted_since).as_secs_f32();
+                frames = 0;
+                counted_since = now;
+            }
+            if now >= status_due {
+                self.publish_status(frame_rate)?;
+                status_due = now + self.status_interval;
+            }
+        }
+        self.client.disconnect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Reads one packet the way a broker would.
+    fn read(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut data = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            data.push(byte[0]);
+            if let Some((kind, start, end)) = split_packet(&data).unwrap() {
+                return (kind, data[start..end].to_vec());
+            }
+        }
+    }
+
+    fn font() -> Font {
+        Font::from_glyphs("empty", 7, 1, HashMap::new())
+    }
+
+    #[test]
+    fn packet_lengths() {
+        let body = vec![7; 321];
+        let bytes = packet(PUBLISH, &body);
+        assert_eq!(&bytes[..3], &[PUBLISH, 0xc1, 0x02]);
+        assert_eq!(split_packet(&bytes), Ok(Some((PUBLISH, 3, 324))));
+        assert_eq!(split_packet(&bytes[..100]), Ok(None));
+        assert!(split_packet(&[PUBLISH, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("brightness", b"42\n"), Ok(Command::Brightness(42)));
+        assert!(Command::parse("brightness", b"101").is_err());
+        assert_eq!(Command::parse("power", b"OFF"), Ok(Command::Power(false)));
+        assert_eq!(Command::parse("text", b"Hello"), Ok(Command::Text("Hello".to_string())));
+        assert!(Command::parse("volume", b"11").is_err());
+    }
+
+    #[test]
+    fn talks_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (kind, body) = read(&mut stream);
+            assert_eq!(kind, CONNECT);
+            assert_eq!(&body[..7], b"\0\x04MQTT\x04");
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let (kind, body) = read(&mut stream);
+            assert_eq!(kind, SUBSCRIBE);
+            assert_eq!(&body[4..body.len() - 1], b"sign/+/set");
+            let mut command = Vec::new();
+            push_string(&mut command, b"sign/scene/set");
+            command.extend_from_slice(b"clock");
+            stream.write_all(&packet(PUBLISH, &command)).unwrap();
+
+            // the status the controller publishes in return
+            (0..5)
+                .map(|_| {
+                    let (kind, body) = read(&mut stream);
+                    assert_eq!(kind, PUBLISH | FLAG_RETAIN);
+                    let publish = Publish::parse(kind, &body).unwrap();
+                    (publish.topic, String::from_utf8(publish.payload).unwrap())
+                })
+                .collect::<HashMap<_, _>>()
+        });
+
+        let client = Client::connect(address, &ConnectOptions::new("test")).unwrap();
+        let mut controller = Controller::new(client, "sign/", font()).unwrap();
+        controller.add_scene("clock", |canvas: &mut Canvas, _: Duration| canvas.fill(&RGB8::new(0, 0, 255)));
+
+        let message = controller.client.poll(Duration::from_secs(5)).unwrap().unwrap();
+        let command = controller.command_for(&message).unwrap().unwrap();
+        assert_eq!(command, Command::Scene("clock".to_string()));
+        controller.apply(command).unwrap();
+        assert_eq!(controller.status(0.0).scene, "clock");
+        assert!(controller.apply(Command::Scene("weather".to_string())).is_err());
+
+        controller.publish_status(30.0).unwrap();
+        let status = broker.join().unwrap();
+        assert_eq!(status["sign/status/scene"], "clock");
+        assert_eq!(status["sign/status/frame_rate"], "30.0");
+        assert_eq!(status["sign/status/power"], "on");
+    }
+}
@@ -0,0 +1,400 @@
+//! An Art-Net 4 node that shows ArtDmx data on the matrix.
+//!
+//! Universes are 15 bit Port-Addresses (net, sub-net, universe) mapped onto
+//! the canvas by a `DmxLayout`. Frames are shown once every universe of the
+//! layout arrived. After an ArtSync the node switches to synchronous mode
+//! and shows frames on ArtSync only, until none came for `SYNC_TIMEOUT`.
+
+use super::dmx::{is_out_of_order, DmxFrame, DmxLayout};
+use super::{receive_datagram, Presenter, MAX_DATAGRAM, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 6454;
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(4);
+
+const ID: &[u8; 8] = b"Art-Net\0";
+const PROTOCOL_VERSION: u16 = 14;
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+const OP_SYNC: u16 = 0x5200;
+
+const POLL_REPLY_SIZE: usize = 239;
+/// Output ports one ArtPollReply describes.
+const PORTS_PER_REPLY: usize = 4;
+/// Port type bits: can output DMX512 from the network.
+const PORT_OUTPUT_DMX: u8 = 0x80;
+/// Good output bits: data is being output.
+const OUTPUT_TRANSMITTING: u8 = 0x80;
+
+/// Combines the parts of an address into a 15 bit Port-Address.
+pub fn port_address(net: u8, sub_net: u8, universe: u8) -> u16 {
+    ((net as u16 & 0x7f) << 8) | ((sub_net as u16 & 0x0f) << 4) | (universe as u16 & 0x0f)
+}
+
+fn header(opcode: u16, size: usize) -> Vec<u8> {
+    let mut bytes = vec![0; size];
+    bytes[0..8].copy_from_slice(ID);
+    bytes[8..10].copy_from_slice(&opcode.to_le_bytes());
+    bytes
+}
+
+/*
+ * Packets
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmxPacket {
+    /// 0 if the sender doesn't number its packets.
+    pub sequence: u8,
+    pub physical: u8,
+    pub port_address: u16,
+    pub channels: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Poll,
+    Dmx(DmxPacket),
+    Sync,
+}
+
+impl Packet {
+    /// Parses the packets a node acts on. Everything else, including other
+    /// nodes' poll replies, is an error.
+    pub fn parse(data: &[u8]) -> Result<Packet, &'static str> {
+        if data.len() < 12 || &data[0..8] != ID {
+            return Err("not an Art-Net packet");
+        }
+        match u16::from_le_bytes([data[8], data[9]]) {
+            OP_POLL => Ok(Packet::Poll),
+            OP_SYNC => Ok(Packet::Sync),
+            OP_DMX => {
+                if data.len() < 18 {
+                    return Err("truncated ArtDmx");
+                }
+                let length = u16::from_be_bytes([data[16], data[17]]) as usize;
+                if 18 + length > data.len() {
+                    return Err("truncated ArtDmx");
+                }
+                Ok(Packet::Dmx(DmxPacket {
+                    sequence: data[12],
+                    physical: data[13],
+                    port_address: u16::from_le_bytes([data[14], data[15]]) & 0x7fff,
+                    channels: data[18..18 + length].to_vec(),
+                }))
+            }
+            _ => Err("unsupported opcode"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = match self {
+            Packet::Poll => header(OP_POLL, 14),
+            Packet::Sync => header(OP_SYNC, 14),
+            Packet::Dmx(dmx) => {
+                let mut bytes = header(OP_DMX, 18 + dmx.channels.len());
+                bytes[12] = dmx.sequence;
+                bytes[13] = dmx.physical;
+                bytes[14..16].copy_from_slice(&dmx.port_address.to_le_bytes());
+                bytes[16..18].copy_from_slice(&(dmx.channels.len() as u16).to_be_bytes());
+                bytes[18..].copy_from_slice(&dmx.channels);
+                bytes
+            }
+        };
+        bytes[10..12].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        bytes
+    }
+}
+
+/*
+ * Node
+ */
+
+/// How the node introduces itself to controllers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub short_name: String,
+    pub long_name: String,
+    /// The address controllers should send to. Taken from the socket when
+    /// it is bound to a specific address; if unspecified, each reply gives
+    /// the address of the interface that reaches the poller.
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+}
+
+impl NodeInfo {
+    pub fn new(short_name: &str, long_name: &str) -> NodeInfo {
+        NodeInfo {
+            short_name: short_name.to_string(),
+            long_name: long_name.to_string(),
+            ip: Ipv4Addr::UNSPECIFIED,
+            mac: [0; 6],
+        }
+    }
+}
+
+impl Default for NodeInfo {
+    fn default() -> NodeInfo {
+        NodeInfo::new("ledmatrix", "ledmatrix Art-Net node")
+    }
+}
+
+pub struct Receiver {
+    socket: UdpSocket,
+    frame: DmxFrame,
+    pub node: NodeInfo,
+    sequences: HashMap<(u16, SocketAddr), u8>,
+    /// When the last ArtSync came, while in synchronous mode.
+    last_sync: Option<Instant>,
+    buffer: Vec<u8>,
+}
+
+impl Receiver {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)` so that
+    /// broadcast polls arrive as well.
+    pub fn bind<A: ToSocketAddrs>(address: A, layout: DmxLayout) -> io::Result<Receiver> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        socket.set_broadcast(true)?;
+
+        let mut node = NodeInfo::default();
+        if let SocketAddr::V4(local) = socket.local_addr()? {
+            node.ip = *local.ip();
+        }
+        Ok(Receiver {
+            socket,
+            frame: DmxFrame::new(layout),
+            node,
+            sequences: HashMap::new(),
+            last_sync: None,
+            buffer: vec![0; MAX_DATAGRAM],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The frame as received so far, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        self.frame.pixels()
+    }
+
+    /// Waits briefly for a packet and handles it, answering polls. Returns
+    /// whether a frame is ready to show.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let (packet, sender) = match receive_datagram(&self.socket, &mut self.buffer)? {
+            Some((size, sender)) => (Packet::parse(&self.buffer[..size]), sender),
+            None => return Ok(false),
+        };
+        match packet {
+            Ok(Packet::Poll) => {
+                for reply in self.poll_replies(sender) {
+                    self.socket.send_to(&reply, sender)?;
+                }
+                Ok(false)
+            }
+            Ok(packet) => Ok(self.apply(&packet, sender, Instant::now())),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Applies an ArtDmx or ArtSync from `sender` received at `now`.
+    /// Returns whether a frame is ready.
+    pub fn apply(&mut self, packet: &Packet, sender: SocketAddr, now: Instant) -> bool {
+        let synchronous = self.last_sync.is_some_and(|last| now.duration_since(last) < SYNC_TIMEOUT);
+        match packet {
+            Packet::Sync => {
+                self.last_sync = Some(now);
+                self.frame.start_next();
+                true
+            }
+            Packet::Dmx(dmx) => {
+                if dmx.sequence != 0 {
+                    if let Some(last) = self.sequences.insert((dmx.port_address, sender), dmx.sequence) {
+                        if is_out_of_order(last, dmx.sequence) {
+                            self.sequences.insert((dmx.port_address, sender), last);
+                            return false;
+                        }
+                    }
+                }
+                if !self.frame.apply_universe(dmx.port_address, &dmx.channels) {
+                    return false;
+                }
+                if !synchronous && self.frame.is_complete() {
+                    self.frame.start_next();
+                    return true;
+                }
+                false
+            }
+            Packet::Poll => false,
+        }
+    }
+
+    /// The address to advertise to `poller`.
+    fn advertised_ip(&self, poller: SocketAddr) -> Ipv4Addr {
+        if !self.node.ip.is_unspecified() {
+            return self.node.ip;
+        }
+        // connecting a UDP socket sends nothing, but picks the interface
+        let local = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|scratch| scratch.connect(poller).and_then(|_| scratch.local_addr()));
+        match local {
+            Ok(SocketAddr::V4(local)) => *local.ip(),
+            _ => self.node.ip,
+        }
+    }
+
+    /// ArtPollReplies to `poller` listing the layout's universes, up to four
+    /// per reply and never mixing nets or sub-nets within one.
+    pub fn poll_replies(&self, poller: SocketAddr) -> Vec<Vec<u8>> {
+        let layout = &self.frame.layout;
+        let universes: Vec<u16> = (0..layout.universe_count()).map(|offset| layout.first_universe + offset).collect();
+
+        let ip = self.advertised_ip(poller);
+        let mut groups: Vec<Vec<u16>> = Vec::new();
+        for universe in universes {
+            match groups.last_mut() {
+                Some(group) if group.len() < PORTS_PER_REPLY && group[0] >> 4 == universe >> 4 => group.push(universe),
+                _ => groups.push(vec![universe]),
+            }
+        }
+
+        groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| self.poll_reply(group, index as u8 + 1, ip))
+            .collect()
+    }
+
+    fn poll_reply(&self, universes: &[u16], bind_index: u8, ip: Ipv4Addr) -> Vec<u8> {
+        let mut bytes = header(OP_POLL_REPLY, POLL_REPLY_SIZE);
+        bytes[10..14].copy_from_slice(&ip.octets());
+        bytes[14..16].copy_from_slice(&DEFAULT_PORT.to_le_bytes());
+        bytes[18] = (universes[0] >> 8) as u8 & 0x7f;
+        bytes[19] = (universes[0] >> 4) as u8 & 0x0f;
+        // status 1: indicators normal, addresses set by the node itself
+        bytes[23] = 0xd0;
+
+        let short_name = self.node.short_name.as_bytes();
+        let short_len = short_name.len().min(17);
+        bytes[26..26 + short_len].copy_from_slice(&short_name[..short_len]);
+        let long_name = self.node.long_name.as_bytes();
+        let long_len = long_name.len().min(63);
+        bytes[44..44 + long_len].copy_from_slice(&long_name[..long_len]);
+        let report = b"#0001 [0000] ok";
+        bytes[108..108 + report.len()].copy_from_slice(report);
+
+        bytes[173] = universes.len() as u8;
+        for (port, universe) in universes.iter().enumerate() {
+            bytes[174 + port] = PORT_OUTPUT_DMX;
+            bytes[182 + port] = OUTPUT_TRANSMITTING;
+            bytes[190 + port] = (*universe & 0x0f) as u8;
+        }
+        // style: a node, not a controller
+        bytes[200] = 0x00;
+        bytes[201..207].copy_from_slice(&self.node.mac);
+        bytes[207..211].copy_from_slice(&ip.octets());
+        bytes[211] = bind_index;
+        // status 2: 15 bit Port-Addresses
+        bytes[212] = 0x08;
+        bytes
+    }
+
+    /// Shows the received frames on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive()? {
+                presenter.present(matrix, self.frame.pixels());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dmx(port_address: u16, sequence: u8, channels: &[u8]) -> Packet {
+        Packet::Dmx(DmxPacket { sequence, physical: 0, port_address, channels: channels.to_vec() })
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packet = dmx(port_address(1, 2, 3), 5, &[1, 2, 3]);
+        assert_eq!(Packet::parse(&packet.to_bytes()), Ok(packet));
+        assert_eq!(Packet::parse(&Packet::Sync.to_bytes()), Ok(Packet::Sync));
+        assert_eq!(port_address(1, 2, 3), 0x123);
+        assert!(Packet::parse(b"Art-Net\0\x00\x21\x00\x0e").is_err());
+    }
+
+    #[test]
+    fn syncs_after_art_sync() {
+        let mut layout = DmxLayout::new(2, 1, 0x10);
+        layout.pixels_per_universe = 1;
+        let mut receiver = Receiver::bind("127.0.0.1:0", layout).unwrap();
+        let sender: SocketAddr = ([127, 0, 0, 1], 9).into();
+        let now = Instant::now();
+
+        assert!(!receiver.apply(&dmx(0x10, 1, &[1, 1, 1]), sender, now));
+        assert!(receiver.apply(&dmx(0x11, 1, &[2, 2, 2]), sender, now));
+        assert!(!receiver.apply(&dmx(0x11, 1, &[3, 3, 3]), sender, now));
+
+        assert!(receiver.apply(&Packet::Sync, sender, now));
+        assert!(!receiver.apply(&dmx(0x10, 2, &[4, 4, 4]), sender, now));
+        assert!(!receiver.apply(&dmx(0x11, 2, &[5, 5, 5]), sender, now));
+        assert!(receiver.apply(&Packet::Sync, sender, now));
+        assert_eq!(receiver.pixels(), &[RGB8::new(4, 4, 4), RGB8::new(5, 5, 5)]);
+
+        // without syncs, it falls back to showing complete frames
+        let later = now + SYNC_TIMEOUT;
+        assert!(!receiver.apply(&dmx(0x10, 3, &[6, 6, 6]), sender, later));
+        assert!(receiver.apply(&dmx(0x11, 3, &[7, 7, 7]), sender, later));
+    }
+
+    #[test]
+    fn answers_polls() {
+        let mut layout = DmxLayout::new(7, 1, port_address(0, 0, 14));
+        layout.pixels_per_universe = 1;
+        // bound to every interface, as usual
+        let mut receiver = Receiver::bind("0.0.0.0:0", layout).unwrap();
+        let controller = UdpSocket::bind("127.0.0.1:0").unwrap();
+        controller.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        controller.send_to(&Packet::Poll.to_bytes(), ("127.0.0.1", port)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut replies = Vec::new();
+        let mut buffer = [0; 512];
+        while replies.len() < 3 {
+            assert!(Instant::now() < deadline, "no poll replies");
+            receiver.receive().unwrap();
+            controller.set_nonblocking(true).unwrap();
+            while let Ok((size, _)) = controller.recv_from(&mut buffer) {
+                replies.push(buffer[..size].to_vec());
+            }
+        }
+
+        // universes 14 and 15 of sub-net 0, then 0..=3 of sub-net 1
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].len(), POLL_REPLY_SIZE);
+        assert_eq!(&replies[0][8..10], &OP_POLL_REPLY.to_le_bytes());
+        assert_eq!((replies[0][19], replies[0][173], &replies[0][190..192]), (0, 2, &[14, 15][..]));
+        assert_eq!((replies[1][19], replies[1][173], &replies[1][190..194]), (1, 4, &[0, 1, 2, 3][..]));
+        assert_eq!(replies[2][190], 4);
+        assert_eq!(&replies[0][26..35], b"ledmatrix");
+        // the interface that reached the controller, not 0.0.0.0
+        assert_eq!(&replies[0][10..14], &[127, 0, 0, 1]);
+        assert_eq!(&replies[0][207..211], &[127, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,176 @@
+//! A Distributed Display Protocol receiver, as sent by xLights and WLED.
+//!
+//! Each packet carries RGB data for a byte offset into the display. Data
+//! accumulates until a packet with the push flag arrives, which shows the
+//! frame, so large frames can span many packets without tearing.
+
+use super::{receive_datagram, Presenter, MAX_DATAGRAM, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+pub const DEFAULT_PORT: u16 = 4048;
+
+const HEADER_SIZE: usize = 10;
+const TIMECODE_SIZE: usize = 4;
+
+const VERSION_MASK: u8 = 0xc0;
+const VERSION_1: u8 = 0x40;
+const FLAG_TIMECODE: u8 = 0x10;
+const FLAG_QUERY: u8 = 0x02;
+const FLAG_PUSH: u8 = 0x01;
+
+/// Data types we accept: undefined, and RGB with 8 bits per channel.
+const DATA_TYPES: [u8; 3] = [0x00, 0x01, 0x0b];
+/// The display itself, and every device.
+const DESTINATIONS: [u8; 2] = [0x01, 0xff];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub push: bool,
+    pub sequence: u8,
+    pub data_type: u8,
+    pub destination: u8,
+    /// Where `data` goes, in bytes from the start of the frame.
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(offset: u32, data: Vec<u8>, push: bool) -> Packet {
+        Packet { push, sequence: 0, data_type: 0x0b, destination: 0x01, offset, data }
+    }
+
+    /// Parses data packets. Queries and replies are errors, as we don't
+    /// answer them.
+    pub fn parse(data: &[u8]) -> Result<Packet, &'static str> {
+        if data.len() < HEADER_SIZE || data[0] & VERSION_MASK != VERSION_1 {
+            return Err("not a DDP version 1 packet");
+        }
+        let flags = data[0];
+        if flags & FLAG_QUERY != 0 {
+            return Err("queries aren't supported");
+        }
+
+        let start = if flags & FLAG_TIMECODE != 0 { HEADER_SIZE + TIMECODE_SIZE } else { HEADER_SIZE };
+        let length = u16::from_be_bytes([data[8], data[9]]) as usize;
+        if start + length > data.len() {
+            return Err("truncated packet");
+        }
+        Ok(Packet {
+            push: flags & FLAG_PUSH != 0,
+            sequence: data[1] & 0x0f,
+            data_type: data[2],
+            destination: data[3],
+            offset: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            data: data[start..start + length].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION_1 | if self.push { FLAG_PUSH } else { 0 }, self.sequence & 0x0f, self.data_type, self.destination];
+        bytes.extend_from_slice(&self.offset.to_be_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+pub struct Receiver {
+    socket: UdpSocket,
+    /// The frame as RGB bytes, row by row.
+    frame: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl Receiver {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, for a
+    /// display of `width` by `height` pixels.
+    pub fn bind<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<Receiver> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        Ok(Receiver { socket, frame: vec![0; (width * height).max(0) as usize * 3], buffer: vec![0; MAX_DATAGRAM] })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn pixels(&self) -> Vec<RGB8> {
+        self.frame.chunks_exact(3).map(|px| RGB8::new(px[0], px[1], px[2])).collect()
+    }
+
+    /// Waits briefly for a packet. Returns whether it asked to push the
+    /// frame to the display.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let packet = match receive_datagram(&self.socket, &mut self.buffer)? {
+            Some((size, _)) => Packet::parse(&self.buffer[..size]),
+            None => return Ok(false),
+        };
+        Ok(match packet {
+            Ok(packet) => self.apply(&packet),
+            Err(_) => false,
+        })
+    }
+
+    /// Copies the packet's data into the frame, dropping what falls past
+    /// its end. Returns the push flag.
+    pub fn apply(&mut self, packet: &Packet) -> bool {
+        if !DATA_TYPES.contains(&packet.data_type) || !DESTINATIONS.contains(&packet.destination) {
+            return false;
+        }
+        let offset = (packet.offset as usize).min(self.frame.len());
+        let length = packet.data.len().min(self.frame.len() - offset);
+        self.frame[offset..offset + length].copy_from_slice(&packet.data[..length]);
+        packet.push
+    }
+
+    /// Shows the received frames on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive()? {
+                presenter.present(matrix, &self.pixels());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parses_packets() {
+        let packet = Packet::new(300, vec![1, 2, 3], true);
+        assert_eq!(Packet::parse(&packet.to_bytes()), Ok(packet));
+
+        // a timecode moves the data back
+        let with_timecode = [0x51, 0x01, 0x0b, 0x01, 0, 0, 0, 3, 0, 3, 9, 9, 9, 9, 7, 8, 9];
+        let parsed = Packet::parse(&with_timecode).unwrap();
+        assert_eq!((parsed.offset, parsed.push, &parsed.data[..]), (3, true, &[7, 8, 9][..]));
+
+        assert!(Packet::parse(&[0x80; 10]).is_err());
+        assert!(Packet::parse(&[0x41, 0, 0x0b, 1, 0, 0, 0, 0, 0, 9, 1]).is_err());
+    }
+
+    #[test]
+    fn pushes_over_udp() {
+        let mut receiver = Receiver::bind("127.0.0.1:0", 2, 1).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = receiver.local_addr().unwrap();
+        sender.send_to(&Packet::new(3, vec![4, 5, 6, 7], false).to_bytes(), address).unwrap();
+        sender.send_to(&Packet::new(0, vec![1, 2, 3], true).to_bytes(), address).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !receiver.receive().unwrap() {
+            assert!(Instant::now() < deadline, "no push arrived");
+        }
+        assert_eq!(receiver.pixels(), vec![RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)]);
+    }
+}
@@ -0,0 +1,157 @@
+//! Mapping DMX universes onto pixels, shared by the sACN and Art-Net
+//! receivers.
+
+use rgb::RGB8;
+
+/// The most RGB pixels one 512 channel universe holds.
+pub const MAX_PIXELS_PER_UNIVERSE: u16 = 170;
+
+/// Whether `sequence` is older than `last`, allowing for wraparound. Within
+/// the last 20 packets counts as out of order, anything else as a restart.
+pub(crate) fn is_out_of_order(last: u8, sequence: u8) -> bool {
+    let difference = sequence.wrapping_sub(last) as i8;
+    difference <= 0 && difference > -20
+}
+
+/// How consecutive pixels of the universes run across the canvas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelOrder {
+    /// Every row left to right.
+    RowMajor,
+    /// Even rows left to right, odd rows right to left, as LED strips are
+    /// usually zigzagged.
+    Serpentine,
+}
+
+/// Which universes drive the canvas and where in each the pixels start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmxLayout {
+    width: i32,
+    height: i32,
+    pub first_universe: u16,
+    /// The 1-based channel of the first pixel's red in every universe.
+    pub start_channel: u16,
+    pub pixels_per_universe: u16,
+    pub order: PixelOrder,
+}
+
+impl DmxLayout {
+    pub fn new(width: i32, height: i32, first_universe: u16) -> DmxLayout {
+        DmxLayout {
+            width,
+            height,
+            first_universe,
+            start_channel: 1,
+            pixels_per_universe: MAX_PIXELS_PER_UNIVERSE,
+            order: PixelOrder::RowMajor,
+        }
+    }
+
+    pub fn get_size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    /// How many universes it takes to cover the canvas.
+    pub fn universe_count(&self) -> u16 {
+        let per_universe = self.pixels_per_universe.max(1) as i32;
+        ((self.width * self.height + per_universe - 1) / per_universe) as u16
+    }
+
+    pub fn contains_universe(&self, universe: u16) -> bool {
+        universe >= self.first_universe && ((universe - self.first_universe) as u32) < self.universe_count() as u32
+    }
+
+    /// Where, row by row, the `index`th pixel of the chain lands.
+    fn canvas_index(&self, index: i32) -> Option<usize> {
+        let (row, mut column) = (index / self.width, index % self.width);
+        if row >= self.height {
+            return None;
+        }
+        if self.order == PixelOrder::Serpentine && row % 2 == 1 {
+            column = self.width - 1 - column;
+        }
+        Some((row * self.width + column) as usize)
+    }
+}
+
+/// A canvas-sized frame filled in universe by universe.
+pub struct DmxFrame {
+    pub layout: DmxLayout,
+    pixels: Vec<RGB8>,
+    received: Vec<bool>,
+}
+
+impl DmxFrame {
+    pub fn new(layout: DmxLayout) -> DmxFrame {
+        let (width, height) = layout.get_size();
+        let universes = layout.universe_count() as usize;
+        DmxFrame { layout, pixels: vec![RGB8::default(); (width * height).max(0) as usize], received: vec![false; universes] }
+    }
+
+    /// Copies the channels of `universe`, the first being channel 1, into
+    /// the frame. Returns false for universes outside the layout.
+    pub fn apply_universe(&mut self, universe: u16, channels: &[u8]) -> bool {
+        if !self.layout.contains_universe(universe) {
+            return false;
+        }
+        let offset = (universe - self.layout.first_universe) as usize;
+        let start = (self.layout.start_channel.max(1) - 1) as usize;
+        let first_pixel = offset as i32 * self.layout.pixels_per_universe as i32;
+
+        let data = channels.get(start..).unwrap_or(&[]);
+        for (pixel, rgb) in data.chunks_exact(3).take(self.layout.pixels_per_universe as usize).enumerate() {
+            if let Some(index) = self.layout.canvas_index(first_pixel + pixel as i32) {
+                self.pixels[index] = RGB8::new(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        self.received[offset] = true;
+        true
+    }
+
+    /// Whether every universe arrived since the last `start_next`.
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+
+    /// Starts collecting universes for the next frame, keeping the pixels.
+    pub fn start_next(&mut self) {
+        self.received.iter_mut().for_each(|received| *received = false);
+    }
+
+    /// The frame row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serpentine_universes() {
+        let mut layout = DmxLayout::new(3, 2, 10);
+        layout.pixels_per_universe = 4;
+        layout.start_channel = 2;
+        layout.order = PixelOrder::Serpentine;
+        assert_eq!(layout.universe_count(), 2);
+        assert!(!layout.contains_universe(9) && layout.contains_universe(11) && !layout.contains_universe(12));
+
+        let mut frame = DmxFrame::new(layout);
+        let channels: Vec<u8> = (0..13).collect();
+        assert!(frame.apply_universe(10, &channels));
+        assert!(!frame.is_complete());
+        assert!(frame.apply_universe(11, &[0, 100, 100, 100, 200, 200, 200]));
+        assert!(frame.is_complete());
+
+        // the fourth pixel starts the second row from its right end
+        let pixels = frame.pixels();
+        assert_eq!(pixels[0], RGB8::new(1, 2, 3));
+        assert_eq!(pixels[5], RGB8::new(10, 11, 12));
+        assert_eq!(pixels[4], RGB8::new(100, 100, 100));
+        assert_eq!(pixels[3], RGB8::new(200, 200, 200));
+
+        frame.start_next();
+        assert!(!frame.is_complete());
+    }
+}
@@ -0,0 +1,390 @@
+//! An E1.31 (streaming ACN, sACN) receiver.
+//!
+//! Universes are mapped onto the canvas by a `DmxLayout`. A frame is shown
+//! once every universe of the layout arrived, or, when the sender uses
+//! universe synchronization, when the matching sync packet arrives.
+
+use super::dmx::{is_out_of_order, DmxFrame, DmxLayout};
+use super::{receive_datagram, Presenter, MAX_DATAGRAM, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 5568;
+pub const DEFAULT_PRIORITY: u8 = 100;
+
+/// A source not heard from for this long is gone, letting lower priority
+/// sources take over (E1.31's network data loss timeout).
+pub const SOURCE_TIMEOUT: Duration = Duration::from_millis(2500);
+
+const ACN_PACKET_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_DATA: u32 = 0x0000_0004;
+const VECTOR_ROOT_EXTENDED: u32 = 0x0000_0008;
+const VECTOR_FRAMING_DATA: u32 = 0x0000_0002;
+const VECTOR_EXTENDED_SYNC: u32 = 0x0000_0001;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+
+const OPTION_PREVIEW: u8 = 0x80;
+const OPTION_STREAM_TERMINATED: u8 = 0x40;
+
+/// Offset of the framing layer, right after the root layer.
+const FRAMING_LAYER: usize = 38;
+/// Offset of the DMX start code in data packets.
+const PROPERTY_VALUES: usize = 125;
+const SYNC_PACKET_SIZE: usize = 49;
+
+/// The multicast group senders address `universe` to.
+pub fn multicast_group(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, high, low)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// The flags and length field opening each layer: 0x7 then the length of
+/// the rest of the packet from that field on.
+fn flags_and_length(length: usize) -> [u8; 2] {
+    (0x7000 | length as u16).to_be_bytes()
+}
+
+/*
+ * Packets
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataPacket {
+    /// Identifies the sender across its packets.
+    pub cid: [u8; 16],
+    pub source_name: String,
+    pub priority: u8,
+    /// The sync packet universe that releases this data, or 0 if it shows
+    /// straight away.
+    pub sync_address: u16,
+    pub sequence: u8,
+    pub preview: bool,
+    pub stream_terminated: bool,
+    pub universe: u16,
+    /// DMX channels, starting with channel 1.
+    pub channels: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncPacket {
+    pub cid: [u8; 16],
+    pub sequence: u8,
+    pub sync_address: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Data(DataPacket),
+    Sync(SyncPacket),
+}
+
+impl Packet {
+    pub fn parse(data: &[u8]) -> Result<Packet, &'static str> {
+        if data.len() < FRAMING_LAYER + 6 || &data[4..16] != ACN_PACKET_IDENTIFIER {
+            return Err("not an ACN packet");
+        }
+        let mut cid = [0; 16];
+        cid.copy_from_slice(&data[22..38]);
+
+        match (u32_at(data, 18), u32_at(data, FRAMING_LAYER + 2)) {
+            (VECTOR_ROOT_DATA, VECTOR_FRAMING_DATA) => {
+                if data.len() < PROPERTY_VALUES + 1 {
+                    return Err("truncated data packet");
+                }
+                if data[117] != VECTOR_DMP_SET_PROPERTY {
+                    return Err("unknown DMP vector");
+                }
+                let property_count = u16_at(data, 123) as usize;
+                let end = PROPERTY_VALUES + property_count;
+                if property_count == 0 || end > data.len() {
+                    return Err("bad property count");
+                }
+                if data[PROPERTY_VALUES] != 0 {
+                    return Err("not DMX512 level data");
+                }
+
+                let name = &data[44..108];
+                let name_end = name.iter().position(|byte| *byte == 0).unwrap_or(name.len());
+                let options = data[112];
+                Ok(Packet::Data(DataPacket {
+                    cid,
+                    source_name: String::from_utf8_lossy(&name[..name_end]).into_owned(),
+                    priority: data[108],
+                    sync_address: u16_at(data, 109),
+                    sequence: data[111],
+                    preview: options & OPTION_PREVIEW != 0,
+                    stream_terminated: options & OPTION_STREAM_TERMINATED != 0,
+                    universe: u16_at(data, 113),
+                    channels: data[PROPERTY_VALUES + 1..end].to_vec(),
+                }))
+            }
+            (VECTOR_ROOT_EXTENDED, VECTOR_EXTENDED_SYNC) => {
+                if data.len() < SYNC_PACKET_SIZE {
+                    return Err("truncated sync packet");
+                }
+                Ok(Packet::Sync(SyncPacket { cid, sequence: data[44], sync_address: u16_at(data, 45) }))
+            }
+            _ => Err("unsupported packet"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (root_vector, cid, size) = match self {
+            Packet::Data(data) => (VECTOR_ROOT_DATA, &data.cid, PROPERTY_VALUES + 1 + data.channels.len()),
+            Packet::Sync(sync) => (VECTOR_ROOT_EXTENDED, &sync.cid, SYNC_PACKET_SIZE),
+        };
+
+        let mut bytes = vec![0; size];
+        bytes[0..2].copy_from_slice(&0x0010u16.to_be_bytes());
+        bytes[4..16].copy_from_slice(ACN_PACKET_IDENTIFIER);
+        bytes[16..18].copy_from_slice(&flags_and_length(size - 16));
+        bytes[18..22].copy_from_slice(&root_vector.to_be_bytes());
+        bytes[22..38].copy_from_slice(cid);
+        bytes[38..40].copy_from_slice(&flags_and_length(size - FRAMING_LAYER));
+
+        match self {
+            Packet::Data(data) => {
+                bytes[40..44].copy_from_slice(&VECTOR_FRAMING_DATA.to_be_bytes());
+                let name = data.source_name.as_bytes();
+                let name_len = name.len().min(63);
+                bytes[44..44 + name_len].copy_from_slice(&name[..name_len]);
+                bytes[108] = data.priority;
+                bytes[109..111].copy_from_slice(&data.sync_address.to_be_bytes());
+                bytes[111] = data.sequence;
+                bytes[112] = if data.preview { OPTION_PREVIEW } else { 0 }
+                    | if data.stream_terminated { OPTION_STREAM_TERMINATED } else { 0 };
+                bytes[113..115].copy_from_slice(&data.universe.to_be_bytes());
+                bytes[115..117].copy_from_slice(&flags_and_length(size - 115));
+                bytes[117] = VECTOR_DMP_SET_PROPERTY;
+                bytes[118] = 0xa1;
+                bytes[121..123].copy_from_slice(&1u16.to_be_bytes());
+                bytes[123..125].copy_from_slice(&(data.channels.len() as u16 + 1).to_be_bytes());
+                bytes[PROPERTY_VALUES + 1..].copy_from_slice(&data.channels);
+            }
+            Packet::Sync(sync) => {
+                bytes[40..44].copy_from_slice(&VECTOR_EXTENDED_SYNC.to_be_bytes());
+                bytes[44] = sync.sequence;
+                bytes[45..47].copy_from_slice(&sync.sync_address.to_be_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+/*
+ * Receiver
+ */
+
+struct Source {
+    cid: [u8; 16],
+    priority: u8,
+    last_seen: Instant,
+}
+
+pub struct Receiver {
+    socket: UdpSocket,
+    frame: DmxFrame,
+    /// The source currently in control of each universe.
+    sources: HashMap<u16, Source>,
+    sequences: HashMap<(u16, [u8; 16]), u8>,
+    /// Data arrived that waits for a sync packet to this universe.
+    pending_sync: Option<u16>,
+    /// Also show packets the sender marked as preview only.
+    pub accept_preview: bool,
+    buffer: Vec<u8>,
+}
+
+impl Receiver {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`. Call
+    /// `join_multicast` to also receive multicast traffic.
+    pub fn bind<A: ToSocketAddrs>(address: A, layout: DmxLayout) -> io::Result<Receiver> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        Ok(Receiver {
+            socket,
+            frame: DmxFrame::new(layout),
+            sources: HashMap::new(),
+            sequences: HashMap::new(),
+            pending_sync: None,
+            accept_preview: false,
+            buffer: vec![0; MAX_DATAGRAM],
+        })
+    }
+
+    /// Joins the multicast groups of every universe of the layout, and of
+    /// `sync_universe` if synchronization is multicast too.
+    pub fn join_multicast(&self, interface: Ipv4Addr, sync_universe: Option<u16>) -> io::Result<()> {
+        let layout = &self.frame.layout;
+        let universes = (0..layout.universe_count()).map(|offset| layout.first_universe + offset);
+        for universe in universes.chain(sync_universe) {
+            self.socket.join_multicast_v4(&multicast_group(universe), &interface)?;
+        }
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The frame as received so far, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        self.frame.pixels()
+    }
+
+    /// Waits briefly for a packet and applies it. Returns whether a frame
+    /// is complete and ready to show.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let packet = match receive_datagram(&self.socket, &mut self.buffer)? {
+            Some((size, _)) => Packet::parse(&self.buffer[..size]),
+            None => return Ok(false),
+        };
+        Ok(match packet {
+            Ok(packet) => self.apply(&packet, Instant::now()),
+            Err(_) => false,
+        })
+    }
+
+    /// Applies a packet received at `now`. Returns whether a frame is ready.
+    pub fn apply(&mut self, packet: &Packet, now: Instant) -> bool {
+        match packet {
+            Packet::Data(data) => self.apply_data(data, now),
+            Packet::Sync(sync) => {
+                if self.pending_sync == Some(sync.sync_address) {
+                    self.pending_sync = None;
+                    self.frame.start_next();
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    fn apply_data(&mut self, data: &DataPacket, now: Instant) -> bool {
+        if !self.frame.layout.contains_universe(data.universe) || (data.preview && !self.accept_preview) {
+            return false;
+        }
+
+        if let Some(last) = self.sequences.insert((data.universe, data.cid), data.sequence) {
+            if is_out_of_order(last, data.sequence) {
+                self.sequences.insert((data.universe, data.cid), last);
+                return false;
+            }
+        }
+
+        // the highest priority source wins; a lower one takes over once it's gone
+        let in_control = match self.sources.get(&data.universe) {
+            Some(source) if source.cid == data.cid => true,
+            Some(source) => data.priority > source.priority || now.duration_since(source.last_seen) >= SOURCE_TIMEOUT,
+            None => true,
+        };
+        if !in_control {
+            return false;
+        }
+        if data.stream_terminated {
+            self.sources.remove(&data.universe);
+            return false;
+        }
+        self.sources.insert(data.universe, Source { cid: data.cid, priority: data.priority, last_seen: now });
+
+        self.frame.apply_universe(data.universe, &data.channels);
+        if data.sync_address != 0 {
+            self.pending_sync = Some(data.sync_address);
+            return false;
+        }
+        if self.pending_sync.is_none() && self.frame.is_complete() {
+            self.frame.start_next();
+            return true;
+        }
+        false
+    }
+
+    /// Shows the received frames on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive()? {
+                presenter.present(matrix, self.frame.pixels());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(universe: u16, sequence: u8, priority: u8, cid: u8, channels: &[u8]) -> Packet {
+        Packet::Data(DataPacket {
+            cid: [cid; 16],
+            source_name: "desk".to_string(),
+            priority,
+            sync_address: 0,
+            sequence,
+            preview: false,
+            stream_terminated: false,
+            universe,
+            channels: channels.to_vec(),
+        })
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packet = data(7, 3, DEFAULT_PRIORITY, 1, &[1, 2, 3]);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), 129);
+        assert_eq!(Packet::parse(&bytes), Ok(packet));
+
+        let sync = Packet::Sync(SyncPacket { cid: [2; 16], sequence: 9, sync_address: 500 });
+        assert_eq!(Packet::parse(&sync.to_bytes()), Ok(sync));
+        assert!(Packet::parse(&[0; 200]).is_err());
+        assert_eq!(multicast_group(258), Ipv4Addr::new(239, 255, 1, 2));
+    }
+
+    #[test]
+    fn sequence_and_priority() {
+        let mut layout = DmxLayout::new(2, 1, 1);
+        layout.pixels_per_universe = 1;
+        let mut receiver = Receiver::bind("127.0.0.1:0", layout).unwrap();
+        let now = Instant::now();
+
+        assert!(!receiver.apply(&data(1, 10, 100, 1, &[255, 0, 0]), now));
+        assert!(receiver.apply(&data(2, 10, 100, 1, &[0, 255, 0]), now));
+        // stale sequence numbers and lower priorities are ignored
+        assert!(!receiver.apply(&data(1, 9, 100, 1, &[1, 1, 1]), now));
+        assert!(!receiver.apply(&data(1, 0, 50, 2, &[2, 2, 2]), now));
+        assert_eq!(receiver.pixels()[0], RGB8::new(255, 0, 0));
+        // until the stronger source goes quiet
+        receiver.apply(&data(1, 1, 50, 2, &[3, 3, 3]), now + SOURCE_TIMEOUT);
+        assert_eq!(receiver.pixels()[0], RGB8::new(3, 3, 3));
+    }
+
+    #[test]
+    fn waits_for_sync() {
+        let layout = DmxLayout::new(1, 1, 1);
+        let mut receiver = Receiver::bind("127.0.0.1:0", layout).unwrap();
+        let now = Instant::now();
+
+        let mut packet = data(1, 1, 100, 1, &[9, 9, 9]);
+        if let Packet::Data(data) = &mut packet {
+            data.sync_address = 77;
+        }
+        assert!(!receiver.apply(&packet, now));
+        assert!(!receiver.apply(&Packet::Sync(SyncPacket { cid: [1; 16], sequence: 0, sync_address: 5 }), now));
+        assert!(receiver.apply(&Packet::Sync(SyncPacket { cid: [1; 16], sequence: 0, sync_address: 77 }), now));
+    }
+}
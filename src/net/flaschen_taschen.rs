@@ -0,0 +1,421 @@
+//! The Flaschen-Taschen protocol: every UDP datagram is a binary PPM (P6)
+//! image, optionally followed by a footer `x y z` giving the offset to draw
+//! it at and the layer to draw it on. Newer clients may instead put the
+//! footer in a `#FT: x y z` header comment.
+//!
+//! Layer 0 is the background. On higher layers black is transparent, and a
+//! layer nobody has sent to for `layer_timeout` is dropped, so a crashed
+//! client doesn't leave its overlay behind.
+
+use super::{receive_datagram, Presenter, MAX_DATAGRAM, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::canvas::Canvas;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 1337;
+/// Layers 0 up to but excluding this are accepted.
+pub const LAYERS: usize = 16;
+pub const DEFAULT_LAYER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Bytes we keep a datagram's payload under when sending, leaving room for
+/// the PPM header and footer.
+const MAX_PAYLOAD: usize = 65000;
+
+/*
+ * Packets
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub x: i32,
+    pub y: i32,
+    pub layer: usize,
+    pub width: i32,
+    pub height: i32,
+    /// `width` by `height` pixels, row by row.
+    pub pixels: Vec<RGB8>,
+}
+
+/// Walks the whitespace separated tokens of a PPM header, skipping comments
+/// but remembering a `#FT:` one.
+struct Tokens<'a> {
+    data: &'a [u8],
+    position: usize,
+    ft_comment: Option<&'a [u8]>,
+}
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self) -> Option<&'a [u8]> {
+        loop {
+            while self.position < self.data.len() && self.data[self.position].is_ascii_whitespace() {
+                self.position += 1;
+            }
+            if self.data.get(self.position) != Some(&b'#') {
+                break;
+            }
+            let end = self.data[self.position..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map_or(self.data.len(), |offset| self.position + offset);
+            let comment = &self.data[self.position..end];
+            if comment.starts_with(b"#FT:") {
+                self.ft_comment = Some(&comment[4..]);
+            }
+            self.position = end;
+        }
+
+        let start = self.position;
+        while self.position < self.data.len() && !self.data[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+        if start == self.position {
+            None
+        } else {
+            Some(&self.data[start..self.position])
+        }
+    }
+
+    fn number(&mut self) -> Option<i32> {
+        std::str::from_utf8(self.next()?).ok()?.parse().ok()
+    }
+}
+
+/// `width` times `height`, if that makes an image that fits a datagram.
+fn pixel_count(width: i32, height: i32) -> Option<usize> {
+    let count = (width.max(0) as usize).checked_mul(height.max(0) as usize)?;
+    if count.checked_mul(3)? > MAX_DATAGRAM {
+        return None;
+    }
+    Some(count)
+}
+
+/// Reads up to three numbers for the offset and layer, missing ones being 0.
+fn parse_footer(text: &[u8]) -> Result<(i32, i32, usize), &'static str> {
+    let text = std::str::from_utf8(text).map_err(|_| "footer isn't text")?;
+    let mut values = [0; 3];
+    for (value, token) in values.iter_mut().zip(text.split_whitespace()) {
+        *value = token.parse().map_err(|_| "footer isn't numbers")?;
+    }
+    if values[2] < 0 {
+        return Err("negative layer");
+    }
+    Ok((values[0], values[1], values[2] as usize))
+}
+
+impl Packet {
+    pub fn new(x: i32, y: i32, layer: usize, width: i32, height: i32, pixels: Vec<RGB8>) -> Result<Packet, &'static str> {
+        if width <= 0 || height <= 0 {
+            return Err("empty image");
+        }
+        if pixel_count(width, height) != Some(pixels.len()) {
+            return Err("pixels don't fill the packet");
+        }
+        Ok(Packet { x, y, layer, width, height, pixels })
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Packet, &'static str> {
+        let mut tokens = Tokens { data, position: 0, ft_comment: None };
+        if tokens.next() != Some(b"P6") {
+            return Err("not a binary PPM");
+        }
+        let width = tokens.number().ok_or("bad width")?;
+        let height = tokens.number().ok_or("bad height")?;
+        if tokens.number() != Some(255) {
+            return Err("only 8 bit PPMs are supported");
+        }
+        if width <= 0 || height <= 0 {
+            return Err("empty image");
+        }
+
+        // exactly one whitespace byte separates the header from the pixels
+        let start = tokens.position + 1;
+        let end = start + pixel_count(width, height).ok_or("image too large")? * 3;
+        if end > data.len() {
+            return Err("truncated image");
+        }
+        let pixels = data[start..end].chunks_exact(3).map(|px| RGB8::new(px[0], px[1], px[2])).collect();
+
+        let (x, y, layer) = match tokens.ft_comment {
+            Some(comment) => parse_footer(comment)?,
+            None => parse_footer(&data[end..])?,
+        };
+        Ok(Packet { x, y, layer, width, height, pixels })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        bytes.extend(self.pixels.iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]));
+        bytes.extend(format!("\n{} {} {}\n", self.x, self.y, self.layer).into_bytes());
+        bytes
+    }
+}
+
+/*
+ * Layers
+ */
+
+struct Layer {
+    pixels: Vec<RGB8>,
+    updated: Instant,
+}
+
+/// Combines the layers received into one frame.
+pub struct Compositor {
+    width: i32,
+    height: i32,
+    layers: Vec<Option<Layer>>,
+    pub layer_timeout: Duration,
+}
+
+impl Compositor {
+    pub fn new(width: i32, height: i32) -> Compositor {
+        Compositor {
+            width,
+            height,
+            layers: (0..LAYERS).map(|_| None).collect(),
+            layer_timeout: DEFAULT_LAYER_TIMEOUT,
+        }
+    }
+
+    /// Draws `packet` onto its layer. Packets for layers past `LAYERS` are
+    /// ignored.
+    pub fn apply(&mut self, packet: &Packet, now: Instant) {
+        let (width, height) = (self.width, self.height);
+        let layer = match self.layers.get_mut(packet.layer) {
+            Some(layer) => layer.get_or_insert_with(|| Layer {
+                pixels: vec![RGB8::default(); (width * height) as usize],
+                updated: now,
+            }),
+            None => return,
+        };
+
+        for (index, rgb) in packet.pixels.iter().enumerate() {
+            let x = packet.x.saturating_add(index as i32 % packet.width);
+            let y = packet.y.saturating_add(index as i32 / packet.width);
+            if x >= 0 && y >= 0 && x < width && y < height {
+                layer.pixels[(y * width + x) as usize] = *rgb;
+            }
+        }
+        layer.updated = now;
+    }
+
+    /// Drops the layers above the background that timed out. Returns
+    /// whether any did.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let timeout = self.layer_timeout;
+        let mut expired = false;
+        for layer in self.layers.iter_mut().skip(1) {
+            if layer.as_ref().is_some_and(|layer| now.duration_since(layer.updated) >= timeout) {
+                *layer = None;
+                expired = true;
+            }
+        }
+        expired
+    }
+
+    /// The layers stacked from 0 up, black being transparent above layer 0.
+    pub fn composite(&self) -> Vec<RGB8> {
+        let mut frame = vec![RGB8::default(); (self.width * self.height) as usize];
+        for (index, layer) in self.layers.iter().enumerate() {
+            if let Some(layer) = layer {
+                for (out, rgb) in frame.iter_mut().zip(&layer.pixels) {
+                    if index == 0 || *rgb != RGB8::default() {
+                        *out = *rgb;
+                    }
+                }
+            }
+        }
+        frame
+    }
+}
+
+/*
+ * Server
+ */
+
+pub struct Server {
+    socket: UdpSocket,
+    compositor: Compositor,
+    buffer: Vec<u8>,
+}
+
+impl Server {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, for a
+    /// display of `width` by `height` pixels.
+    pub fn bind<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<Server> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        Ok(Server { socket, compositor: Compositor::new(width, height), buffer: vec![0; MAX_DATAGRAM] })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn compositor(&self) -> &Compositor {
+        &self.compositor
+    }
+
+    pub fn compositor_mut(&mut self) -> &mut Compositor {
+        &mut self.compositor
+    }
+
+    /// Waits briefly for a packet and applies it. Returns whether the
+    /// composited frame changed, by a packet or a layer timing out.
+    /// Malformed packets are dropped.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        if let Some((size, _)) = receive_datagram(&self.socket, &mut self.buffer)? {
+            if let Ok(packet) = Packet::parse(&self.buffer[..size]) {
+                self.compositor.apply(&packet, Instant::now());
+                changed = true;
+            }
+        }
+        Ok(self.compositor.expire(Instant::now()) || changed)
+    }
+
+    /// Shows what clients send on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive()? {
+                presenter.present(matrix, &self.compositor.composite());
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Client
+ */
+
+/// Sends a frame buffer to a Flaschen-Taschen server.
+pub struct Client {
+    socket: UdpSocket,
+    width: i32,
+    height: i32,
+    pixels: Vec<RGB8>,
+    /// Where on the display, and on which layer, the buffer goes.
+    pub x: i32,
+    pub y: i32,
+    pub layer: usize,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<Client> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        let local: SocketAddr = if address.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        Ok(Client { socket, width, height, pixels: vec![RGB8::default(); width.max(0) as usize * height.max(0) as usize], x: 0, y: 0, layer: 0 })
+    }
+
+    pub fn get_size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, rgb: &RGB8) {
+        if x >= 0 && y >= 0 && x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = *rgb;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.iter_mut().for_each(|pixel| *pixel = RGB8::default());
+    }
+
+    /// Copies what is drawn on `canvas` into the buffer, from its top left
+    /// corner, and sends it.
+    pub fn send_canvas(&mut self, canvas: &Canvas) -> io::Result<()> {
+        let (width, _) = canvas.get_size();
+        for (index, rgb) in canvas.pixels().iter().enumerate() {
+            self.set_pixel(index as i32 % width, index as i32 / width, rgb);
+        }
+        self.send()
+    }
+
+    /// Sends the buffer, split into bands of rows if it doesn't fit one
+    /// datagram.
+    pub fn send(&self) -> io::Result<()> {
+        let row_bytes = (self.width * 3).max(1) as usize;
+        let rows_per_packet = (MAX_PAYLOAD / row_bytes).max(1) as i32;
+        let mut row = 0;
+        let width = self.width.max(0) as usize;
+        while row < self.height {
+            let rows = rows_per_packet.min(self.height - row);
+            let start = row as usize * width;
+            let end = start + rows as usize * width;
+            let pixels = self.pixels[start..end].to_vec();
+            let packet = Packet::new(self.x, self.y.saturating_add(row), self.layer, self.width, rows, pixels)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            self.socket.send(&packet.to_bytes())?;
+            row += rows;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+
+    #[test]
+    fn parses_footer_and_comment() {
+        let packet = Packet::new(3, 4, 2, 2, 1, vec![RED, BLUE]).unwrap();
+        assert_eq!(Packet::parse(&packet.to_bytes()), Ok(packet));
+
+        let plain = Packet::parse(b"P6 1 1 255\n\x01\x02\x03").unwrap();
+        assert_eq!((plain.x, plain.y, plain.layer, plain.pixels[0]), (0, 0, 0, RGB8::new(1, 2, 3)));
+
+        let commented = Packet::parse(b"P6\n#FT: 5 6 7\n# other\n1 1\n255\n\x01\x02\x03").unwrap();
+        assert_eq!((commented.x, commented.y, commented.layer), (5, 6, 7));
+
+        assert!(Packet::parse(b"P6\n2 2\n255\n\x01\x02\x03").is_err());
+        assert!(Packet::parse(b"P3\n1 1\n255\n1 2 3").is_err());
+        assert_eq!(Packet::parse(b"P6 100000 100000 255\n\x01\x02\x03"), Err("image too large"));
+        assert!(Packet::new(0, 0, 0, 2, 2, vec![RED]).is_err());
+    }
+
+    #[test]
+    fn layers_composite_and_expire() {
+        let start = Instant::now();
+        let mut compositor = Compositor::new(2, 1);
+        compositor.apply(&Packet::new(0, 0, 0, 2, 1, vec![RED, RED]).unwrap(), start);
+        compositor.apply(&Packet::new(0, 0, 1, 2, 1, vec![RGB8::default(), BLUE]).unwrap(), start);
+        assert_eq!(compositor.composite(), vec![RED, BLUE]);
+
+        assert!(!compositor.expire(start + Duration::from_secs(1)));
+        assert!(compositor.expire(start + DEFAULT_LAYER_TIMEOUT));
+        assert_eq!(compositor.composite(), vec![RED, RED]);
+    }
+
+    #[test]
+    fn client_to_server_over_localhost() {
+        let mut server = Server::bind("127.0.0.1:0", 4, 2).unwrap();
+        let mut client = Client::connect(server.local_addr().unwrap(), 2, 1).unwrap();
+        client.x = 2;
+        client.y = 1;
+        client.set_pixel(1, 0, &BLUE);
+        client.send().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.receive().unwrap() {
+            assert!(Instant::now() < deadline, "no packet arrived");
+        }
+        let frame = server.compositor().composite();
+        assert_eq!(frame[7], BLUE);
+        assert_eq!(frame.iter().filter(|pixel| **pixel != RGB8::default()).count(), 1);
+    }
+}
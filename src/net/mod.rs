@@ -0,0 +1,57 @@
+//! Receivers and senders for the network protocols LED displays are
+//! commonly driven with.
+
+pub mod artnet;
+pub mod ddp;
+pub mod dmx;
+pub mod e131;
+pub mod flaschen_taschen;
+pub mod opc;
+pub mod remote;
+pub mod tpm2;
+
+use super::animation::STOP_POLL_INTERVAL;
+use super::canvas::{Canvas, Rect};
+use super::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// How long receivers wait on their socket before checking their stop
+/// handle or timeouts again.
+pub(crate) const RECEIVE_TIMEOUT: Duration = STOP_POLL_INTERVAL;
+
+/// Largest UDP payload there is.
+pub(crate) const MAX_DATAGRAM: usize = 65536;
+
+/// Receives one datagram into `buffer`, or `None` if nothing arrived within
+/// the socket's read timeout.
+pub(crate) fn receive_datagram(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+    match socket.recv_from(buffer) {
+        Ok(received) => Ok(Some(received)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Double buffers frames received over the network onto a matrix.
+pub(crate) struct Presenter {
+    displayed: Canvas,
+    offscreen: Canvas,
+}
+
+impl Presenter {
+    pub(crate) fn new(matrix: &mut Matrix) -> Presenter {
+        Presenter { displayed: matrix.get_canvas(), offscreen: matrix.create_offscreen_canvas() }
+    }
+
+    /// Shows a full frame of pixels, row by row, on the next vsync.
+    pub(crate) fn present(&mut self, matrix: &mut Matrix, pixels: &[RGB8]) {
+        let full_canvas = Rect::from_canvas(&self.offscreen);
+        self.offscreen.set_pixels(&full_canvas, pixels);
+        matrix.swap_canvas_on_vsync(&mut self.offscreen, &mut self.displayed);
+        std::mem::swap(&mut self.offscreen, &mut self.displayed);
+    }
+}
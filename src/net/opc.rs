@@ -0,0 +1,378 @@
+//! An Open Pixel Control server.
+//!
+//! OPC messages are a channel, a command and a big endian length followed
+//! by that many bytes of data, sent over TCP. Only command 0, "set pixel
+//! colors" with 8 bit RGB data, is acted on. Each channel can be mapped to
+//! a rectangle of the canvas that its pixels fill row by row; channel 0
+//! goes to every mapped channel, or to the whole canvas if none are.
+
+use super::{Presenter, RECEIVE_TIMEOUT};
+use crate::animation::{StopHandle, STOP_POLL_INTERVAL};
+use crate::canvas::Rect;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const DEFAULT_PORT: u16 = 7890;
+pub const BROADCAST_CHANNEL: u8 = 0;
+pub const SET_PIXEL_COLORS: u8 = 0;
+
+const HEADER_SIZE: usize = 4;
+/// Messages waiting for `Server::receive`; clients sending faster than
+/// they are shown wait for room.
+const QUEUE_LENGTH: usize = 4;
+
+/*
+ * Messages
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub channel: u8,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn set_pixel_colors(channel: u8, pixels: &[RGB8]) -> Message {
+        let data = pixels.iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]).collect();
+        Message { channel, command: SET_PIXEL_COLORS, data }
+    }
+
+    /// Reads the next message, or `None` if the stream ended between
+    /// messages.
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Option<Message>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match input.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "message cut short")),
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut data = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        input.read_exact(&mut data)?;
+        Ok(Some(Message { channel: header[0], command: header[1], data }))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let length = self.data.len().min(u16::MAX as usize);
+        out.write_all(&[self.channel, self.command])?;
+        out.write_all(&(length as u16).to_be_bytes())?;
+        out.write_all(&self.data[..length])
+    }
+
+    /// The pixels of a set pixel colors message.
+    pub fn pixels(&self) -> Vec<RGB8> {
+        self.data.chunks_exact(3).map(|px| RGB8::new(px[0], px[1], px[2])).collect()
+    }
+}
+
+/*
+ * Server
+ */
+
+/// Who gets the display when several clients send at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Every message is shown, whoever sent it.
+    LastWriterWins,
+    /// Clients from addresses earlier in the list override later ones and
+    /// unlisted ones, for as long as they stay connected. Among equals the
+    /// last writer wins.
+    Priority(Vec<IpAddr>),
+}
+
+enum Event {
+    Connected(usize, SocketAddr),
+    Message(usize, Message),
+    Disconnected(usize),
+}
+
+struct Client {
+    address: SocketAddr,
+    has_sent: bool,
+}
+
+pub struct Server {
+    local_addr: SocketAddr,
+    events: Receiver<Event>,
+    clients: HashMap<usize, Client>,
+    channels: HashMap<u8, Rect>,
+    pub policy: Policy,
+    width: i32,
+    height: i32,
+    pixels: Vec<RGB8>,
+    /// Ends the accepting thread.
+    accepting: StopHandle,
+    /// The open connections, shut down on drop to end their threads.
+    streams: Arc<Mutex<HashMap<usize, TcpStream>>>,
+}
+
+/// Forwards each message of one connection to the server.
+fn serve_client(id: usize, mut stream: TcpStream, events: SyncSender<Event>, streams: &Mutex<HashMap<usize, TcpStream>>) {
+    while let Ok(Some(message)) = Message::read_from(&mut stream) {
+        if events.send(Event::Message(id, message)).is_err() {
+            break;
+        }
+    }
+    streams.lock().unwrap().remove(&id);
+    let _ = events.send(Event::Disconnected(id));
+}
+
+impl Server {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, for a
+    /// display of `width` by `height` pixels. Every client gets a thread
+    /// that parses its messages.
+    pub fn bind<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<Server> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let (sender, events) = mpsc::sync_channel(QUEUE_LENGTH);
+        let accepting = StopHandle::new();
+        let streams = Arc::new(Mutex::new(HashMap::new()));
+
+        let (stop, open) = (accepting.clone(), streams.clone());
+        thread::spawn(move || {
+            let mut id = 0;
+            while !stop.is_stopped() {
+                let (stream, address) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(_) => {
+                        thread::sleep(STOP_POLL_INTERVAL);
+                        continue;
+                    }
+                };
+                let clone = match stream.set_nonblocking(false).and_then(|_| stream.try_clone()) {
+                    Ok(clone) => clone,
+                    Err(_) => continue,
+                };
+                {
+                    // checked under the lock, so drop either sees this
+                    // stream or we see the stop
+                    let mut open = open.lock().unwrap();
+                    if stop.is_stopped() {
+                        return;
+                    }
+                    open.insert(id, clone);
+                }
+                // the server is gone once nobody listens to events
+                if sender.send(Event::Connected(id, address)).is_err() {
+                    return;
+                }
+                let (sender, open) = (sender.clone(), open.clone());
+                thread::spawn(move || serve_client(id, stream, sender, &open));
+                id += 1;
+            }
+        });
+
+        Ok(Server {
+            local_addr,
+            events,
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            policy: Policy::LastWriterWins,
+            width,
+            height,
+            pixels: vec![RGB8::default(); (width * height).max(0) as usize],
+            accepting,
+            streams,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends the pixels of `channel` into `at`, row by row.
+    pub fn map_channel(&mut self, channel: u8, at: Rect) {
+        self.channels.insert(channel, at);
+    }
+
+    /// The frame as received so far, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.pixels
+    }
+
+    /// Waits briefly for messages and applies all that arrived. Returns
+    /// whether the frame changed.
+    pub fn receive(&mut self) -> bool {
+        let mut changed = false;
+        let mut event = self.events.recv_timeout(RECEIVE_TIMEOUT);
+        loop {
+            match event {
+                Ok(Event::Connected(id, address)) => {
+                    self.clients.insert(id, Client { address, has_sent: false });
+                }
+                Ok(Event::Disconnected(id)) => {
+                    self.clients.remove(&id);
+                }
+                Ok(Event::Message(id, message)) => changed |= self.apply(id, &message),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+            event = self.events.try_recv().map_err(|_| RecvTimeoutError::Timeout);
+        }
+        changed
+    }
+
+    /// Lower is more important.
+    fn rank(&self, address: &SocketAddr) -> usize {
+        match &self.policy {
+            Policy::LastWriterWins => 0,
+            Policy::Priority(order) => order.iter().position(|ip| *ip == address.ip()).unwrap_or(order.len()),
+        }
+    }
+
+    fn apply(&mut self, id: usize, message: &Message) -> bool {
+        if message.command != SET_PIXEL_COLORS {
+            return false;
+        }
+        let address = match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.has_sent = true;
+                client.address
+            }
+            None => return false,
+        };
+        let rank = self.rank(&address);
+        let outranked = self
+            .clients
+            .values()
+            .any(|client| client.has_sent && self.rank(&client.address) < rank);
+        if outranked {
+            return false;
+        }
+
+        let full_canvas = Rect::new(0, 0, self.width, self.height);
+        let targets: Vec<Rect> = match (message.channel, self.channels.is_empty()) {
+            (_, true) => vec![full_canvas],
+            (BROADCAST_CHANNEL, false) => self.channels.values().copied().collect(),
+            (channel, false) => self.channels.get(&channel).copied().into_iter().collect(),
+        };
+
+        let pixels = message.pixels();
+        for at in &targets {
+            self.fill(at, &pixels);
+        }
+        !targets.is_empty()
+    }
+
+    fn fill(&mut self, at: &Rect, pixels: &[RGB8]) {
+        if at.width <= 0 {
+            return;
+        }
+        for (index, rgb) in pixels.iter().take((at.width * at.height.max(0)) as usize).enumerate() {
+            let x = at.x + index as i32 % at.width;
+            let y = at.y + index as i32 / at.width;
+            if x >= 0 && y >= 0 && x < self.width && y < self.height {
+                self.pixels[(y * self.width + x) as usize] = *rgb;
+            }
+        }
+    }
+
+    /// Shows what clients send on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive() {
+                presenter.present(matrix, &self.pixels);
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let streams = self.streams.lock().unwrap();
+        self.accepting.stop();
+        for stream in streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+
+    fn receive_until(server: &mut Server, done: impl Fn(&Server) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(server) {
+            assert!(Instant::now() < deadline, "timed out");
+            server.receive();
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = Message::set_pixel_colors(2, &[RED, BLUE]);
+        let mut bytes = Vec::new();
+        message.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], &[2, 0, 0, 6]);
+
+        let mut input = &bytes[..];
+        assert_eq!(Message::read_from(&mut input).unwrap(), Some(message));
+        assert_eq!(Message::read_from(&mut input).unwrap(), None);
+        assert!(Message::read_from(&mut &bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn channels_map_to_rectangles() {
+        let mut server = Server::bind("127.0.0.1:0", 4, 2).unwrap();
+        server.map_channel(1, Rect::new(0, 0, 2, 2));
+        server.map_channel(2, Rect::new(2, 0, 2, 2));
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        Message::set_pixel_colors(2, &[RED, RED, RED]).write_to(&mut client).unwrap();
+        Message::set_pixel_colors(BROADCAST_CHANNEL, &[BLUE]).write_to(&mut client).unwrap();
+
+        receive_until(&mut server, |server| server.pixels()[0] == BLUE);
+        assert_eq!(server.pixels()[2], BLUE);
+        assert_eq!(server.pixels()[3], RED);
+        assert_eq!(server.pixels()[6], RED);
+        assert_eq!(server.pixels()[7], RGB8::default());
+    }
+
+    #[test]
+    fn priority_policy() {
+        let mut server = Server::bind("127.0.0.1:0", 1, 1).unwrap();
+        let (important, other): (SocketAddr, SocketAddr) = (([10, 0, 0, 1], 1).into(), ([10, 0, 0, 2], 1).into());
+        server.policy = Policy::Priority(vec![important.ip()]);
+        server.clients.insert(1, Client { address: important, has_sent: false });
+        server.clients.insert(2, Client { address: other, has_sent: false });
+
+        assert!(server.apply(2, &Message::set_pixel_colors(0, &[BLUE])));
+        assert!(server.apply(1, &Message::set_pixel_colors(0, &[RED])));
+        assert!(!server.apply(2, &Message::set_pixel_colors(0, &[BLUE])));
+        assert_eq!(server.pixels()[0], RED);
+
+        // once the important client leaves, the others get through again
+        server.clients.remove(&1);
+        assert!(server.apply(2, &Message::set_pixel_colors(0, &[BLUE])));
+        assert_eq!(server.pixels()[0], BLUE);
+    }
+
+    #[test]
+    fn dropping_closes_connections() {
+        let mut server = Server::bind("127.0.0.1:0", 1, 1).unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        receive_until(&mut server, |server| server.clients.len() == 1);
+        drop(server);
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,382 @@
+//! Drive a matrix over the network.
+//!
+//! `Matrix::connect` gives a matrix that behaves like the hardware one, but
+//! sends every swapped frame to a `Server` running where the panels are,
+//! such as `ledmatrix-remote-server`. The protocol runs over TCP:
+//!
+//! * On connect the server sends `LMRB`, a version byte, the width and
+//!   height as big endian u16s and the brightness.
+//! * The client sends frames as `0x01` followed by the RGB pixels row by
+//!   row, and brightness changes as `0x02` and the new value.
+//! * The server answers each frame with `0x01` once it's on the panels, so
+//!   swapping waits for the remote vsync like it would on the hardware.
+
+use super::{Presenter, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 7892;
+
+const MAGIC: &[u8; 4] = b"LMRB";
+const VERSION: u8 = 1;
+const HELLO_SIZE: usize = 10;
+const FRAME: u8 = 0x01;
+const BRIGHTNESS: u8 = 0x02;
+const FRAME_SHOWN: u8 = 0x01;
+
+/// Most pixels a server may announce, far more than any chain of panels.
+const MAX_PIXELS: usize = 1 << 20;
+/// How long the client waits for the server to say hello or show a frame.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a client that lost its server tries to get it back.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the server tells each client on connect.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Hello {
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    pub(crate) brightness: u8,
+}
+
+impl Hello {
+    fn to_bytes(self) -> [u8; HELLO_SIZE] {
+        let mut bytes = [0; HELLO_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = VERSION;
+        bytes[5..7].copy_from_slice(&(self.width as u16).to_be_bytes());
+        bytes[7..9].copy_from_slice(&(self.height as u16).to_be_bytes());
+        bytes[9] = self.brightness;
+        bytes
+    }
+
+    fn parse(data: &[u8; HELLO_SIZE]) -> Result<Hello, &'static str> {
+        if &data[..4] != MAGIC {
+            return Err("not a remote matrix server");
+        }
+        if data[4] != VERSION {
+            return Err("unsupported protocol version");
+        }
+        let width = u16::from_be_bytes([data[5], data[6]]) as usize;
+        let height = u16::from_be_bytes([data[7], data[8]]) as usize;
+        if width == 0 || height == 0 {
+            return Err("server has an empty display");
+        }
+        if width * height > MAX_PIXELS {
+            return Err("server display too large");
+        }
+        Ok(Hello { width: width as i32, height: height as i32, brightness: data[9] })
+    }
+
+    pub(crate) fn pixel_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+/*
+ * Client
+ */
+
+/// The client side, owned by a `Matrix` made with `Matrix::connect`.
+pub(crate) struct Connection {
+    addresses: Vec<SocketAddr>,
+    stream: Option<TcpStream>,
+    hello: Hello,
+    /// Set by the client, and sent again after reconnecting.
+    brightness: Option<u8>,
+    retry_at: Instant,
+}
+
+fn handshake(addresses: &[SocketAddr]) -> io::Result<(TcpStream, Hello)> {
+    let mut stream = TcpStream::connect(addresses)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut hello = [0; HELLO_SIZE];
+    stream.read_exact(&mut hello)?;
+    let hello = Hello::parse(&hello).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok((stream, hello))
+}
+
+impl Connection {
+    pub(crate) fn open<A: ToSocketAddrs>(address: A) -> io::Result<Connection> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let (stream, hello) = handshake(&addresses)?;
+        Ok(Connection { addresses, stream: Some(stream), hello, brightness: None, retry_at: Instant::now() })
+    }
+
+    pub(crate) fn hello(&self) -> Hello {
+        self.hello
+    }
+
+    /// Gets the connection back if it was lost and it's time to retry.
+    /// Servers of a different size are not used.
+    fn reconnect(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() && Instant::now() >= self.retry_at {
+            self.retry_at = Instant::now() + RECONNECT_INTERVAL;
+            if let Ok((mut stream, hello)) = handshake(&self.addresses) {
+                let resend = self.brightness.map(|brightness| [BRIGHTNESS, brightness]);
+                let same_size = (hello.width, hello.height) == (self.hello.width, self.hello.height);
+                if same_size && resend.map_or(Ok(()), |message| stream.write_all(&message)).is_ok() {
+                    self.stream = Some(stream);
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+
+    /// Sends `message`, waiting for the server to show it if it's a frame.
+    /// While the server is unreachable messages are dropped, so a program
+    /// keeps running through a server restart.
+    fn send(&mut self, message: &[u8]) {
+        let stream = match self.reconnect() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut answer = [0];
+        let sent = stream.write_all(message).and_then(|_| match message[0] {
+            FRAME => stream.read_exact(&mut answer),
+            _ => Ok(()),
+        });
+        if sent.is_err() {
+            self.stream = None;
+        }
+    }
+
+    pub(crate) fn send_frame(&mut self, pixels: &[RGB8]) {
+        let mut message = Vec::with_capacity(1 + pixels.len() * 3);
+        message.push(FRAME);
+        message.extend(pixels.iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]));
+        self.send(&message);
+    }
+
+    pub(crate) fn send_brightness(&mut self, brightness: u8) {
+        self.brightness = Some(brightness);
+        self.send(&[BRIGHTNESS, brightness]);
+    }
+}
+
+/*
+ * Server
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Update {
+    /// A new frame is in `pixels`; `acknowledge` it once it's shown.
+    Frame,
+    Brightness(u8),
+}
+
+/// Shows what one client at a time sends; a newly connecting client takes
+/// over from the current one.
+pub struct Server {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    width: i32,
+    height: i32,
+    /// Reported to clients when they connect.
+    pub brightness: u8,
+    pixels: Vec<RGB8>,
+}
+
+impl Server {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, for a
+    /// display of `width` by `height` pixels.
+    pub fn bind<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<Server> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            width,
+            height,
+            brightness: 100,
+            pixels: vec![RGB8::default(); (width * height).max(0) as usize],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The last frame received, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.pixels
+    }
+
+    fn welcome(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        let hello = Hello { width: self.width, height: self.height, brightness: self.brightness };
+        stream.write_all(&hello.to_bytes())?;
+        self.client = Some(stream);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Takes the first whole message out of the buffer.
+    fn take_message(&mut self) -> Option<Update> {
+        let frame_size = 1 + self.pixels.len() * 3;
+        match self.buffer.first() {
+            Some(&FRAME) if self.buffer.len() >= frame_size => {
+                for (pixel, rgb) in self.pixels.iter_mut().zip(self.buffer[1..frame_size].chunks_exact(3)) {
+                    *pixel = RGB8::new(rgb[0], rgb[1], rgb[2]);
+                }
+                self.buffer.drain(..frame_size);
+                Some(Update::Frame)
+            }
+            Some(&BRIGHTNESS) if self.buffer.len() >= 2 => {
+                self.brightness = self.buffer[1];
+                self.buffer.drain(..2);
+                Some(Update::Brightness(self.brightness))
+            }
+            Some(&FRAME) | Some(&BRIGHTNESS) | None => None,
+            Some(_) => {
+                // out of step with the client, so drop it
+                self.client = None;
+                None
+            }
+        }
+    }
+
+    /// Waits briefly for a client message.
+    pub fn receive(&mut self) -> io::Result<Option<Update>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                // a client that leaves during the hello is no loss
+                let _ = self.welcome(stream);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        if let Some(update) = self.take_message() {
+            return Ok(Some(update));
+        }
+
+        let stream = match &mut self.client {
+            Some(stream) => stream,
+            None => {
+                thread::sleep(RECEIVE_TIMEOUT);
+                return Ok(None);
+            }
+        };
+        let mut chunk = [0; 16384];
+        match stream.read(&mut chunk) {
+            Ok(0) => self.client = None,
+            Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(_) => self.client = None,
+        }
+        Ok(self.take_message())
+    }
+
+    /// Tells the client its last frame is shown.
+    pub fn acknowledge(&mut self) {
+        if let Some(stream) = &mut self.client {
+            if stream.write_all(&[FRAME_SHOWN]).is_err() {
+                self.client = None;
+            }
+        }
+    }
+
+    /// Shows what clients send on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        self.brightness = matrix.get_brightness();
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            match self.receive()? {
+                Some(Update::Frame) => {
+                    presenter.present(matrix, &self.pixels);
+                    self.acknowledge();
+                }
+                Some(Update::Brightness(brightness)) => {
+                    matrix.set_brightness(brightness);
+                    // brightness applies when drawing, so draw again
+                    presenter.present(matrix, &self.pixels);
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::PixelLocation;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello { width: 128, height: 64, brightness: 70 };
+        assert_eq!(Hello::parse(&hello.to_bytes()), Ok(hello));
+        assert!(Hello::parse(b"HTTP/1.1 4").is_err());
+        assert!(Hello::parse(&Hello { width: 0, height: 64, brightness: 70 }.to_bytes()).is_err());
+        assert!(Hello::parse(&Hello { width: 65535, height: 65535, brightness: 70 }.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn remote_matrix_drives_server() {
+        let mut server = Server::bind("127.0.0.1:0", 3, 2).unwrap();
+        server.brightness = 60;
+        let address = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            let mut updates = Vec::new();
+            while updates.len() < 2 {
+                if let Some(update) = server.receive().unwrap() {
+                    if update == Update::Frame {
+                        server.acknowledge();
+                    }
+                    updates.push(update);
+                }
+            }
+            (updates, server.pixels().to_vec())
+        });
+
+        let mut matrix = Matrix::connect(address).unwrap();
+        assert_eq!(matrix.get_brightness(), 60);
+        let mut displayed = matrix.get_canvas();
+        let mut offscreen = matrix.create_offscreen_canvas();
+        assert_eq!(offscreen.get_size(), (3, 2));
+
+        matrix.set_brightness(40);
+        offscreen.set_pixel(&PixelLocation { x: 2, y: 1 }, &RGB8::new(9, 8, 7));
+        matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+        assert_eq!(matrix.front_pixels()[5], RGB8::new(9, 8, 7));
+
+        let (updates, pixels) = serving.join().unwrap();
+        assert_eq!(updates, vec![Update::Brightness(40), Update::Frame]);
+        assert_eq!(pixels[5], RGB8::new(9, 8, 7));
+        assert_eq!(pixels[0], RGB8::default());
+    }
+
+    #[test]
+    fn swap_keeps_drawing_on_shown_canvas() {
+        let mut server = Server::bind("127.0.0.1:0", 3, 2).unwrap();
+        let address = server.local_addr().unwrap();
+        let serving = thread::spawn(move || loop {
+            if let Some(Update::Frame) = server.receive().unwrap() {
+                server.acknowledge();
+                return;
+            }
+        });
+
+        let mut matrix = Matrix::connect(address).unwrap();
+        assert_eq!(matrix.front_pixels(), &[RGB8::default(); 6][..]);
+        let mut displayed = matrix.get_canvas();
+        displayed.set_pixel(&PixelLocation { x: 1, y: 0 }, &RGB8::new(1, 2, 3));
+        let mut offscreen = matrix.create_offscreen_canvas();
+        matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+        assert_eq!(displayed.pixels()[1], RGB8::new(1, 2, 3));
+        serving.join().unwrap();
+    }
+}
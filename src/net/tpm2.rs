@@ -0,0 +1,388 @@
+//! TPM2, as sent by Glediator and Jinx!, over a serial port and as TPM2.net
+//! over UDP.
+//!
+//! A serial frame is `0xC9`, a packet type, a big endian payload size, the
+//! payload and `0x36`. TPM2.net packets start with `0x9C` and add a packet
+//! number and count after the size, so frames too big for one datagram can
+//! be split up. Payloads are RGB pixels filling the canvas row by row.
+
+use super::{receive_datagram, Presenter, MAX_DATAGRAM, RECEIVE_TIMEOUT};
+use crate::animation::StopHandle;
+use crate::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+pub const DEFAULT_PORT: u16 = 65506;
+
+const SERIAL_START: u8 = 0xc9;
+const NET_START: u8 = 0x9c;
+const END: u8 = 0x36;
+pub const TYPE_DATA: u8 = 0xda;
+pub const TYPE_COMMAND: u8 = 0xc0;
+pub const TYPE_RESPONSE: u8 = 0xaa;
+
+const SERIAL_HEADER_SIZE: usize = 4;
+const NET_HEADER_SIZE: usize = 6;
+
+/*
+ * Frames
+ */
+
+/// Collects the packets of a frame into canvas-sized pixels.
+pub struct FrameAssembler {
+    parts: Vec<Option<Vec<u8>>>,
+    pixels: Vec<RGB8>,
+}
+
+impl FrameAssembler {
+    pub fn new(width: i32, height: i32) -> FrameAssembler {
+        FrameAssembler { parts: Vec::new(), pixels: vec![RGB8::default(); (width * height).max(0) as usize] }
+    }
+
+    /// Adds packet `number`, counting from 1, of a frame of `total` packets.
+    /// Returns whether that completed the frame. A packet from a frame of a
+    /// different size drops what was collected so far.
+    pub fn add_packet(&mut self, number: u8, total: u8, payload: &[u8]) -> bool {
+        let total = total.max(1) as usize;
+        let number = number.max(1) as usize;
+        if number > total {
+            return false;
+        }
+        if self.parts.len() != total {
+            self.parts = vec![None; total];
+        }
+        self.parts[number - 1] = Some(payload.to_vec());
+        if self.parts.iter().any(Option::is_none) {
+            return false;
+        }
+
+        let data: Vec<u8> = self.parts.drain(..).flatten().flatten().collect();
+        for (pixel, rgb) in self.pixels.iter_mut().zip(data.chunks_exact(3)) {
+            *pixel = RGB8::new(rgb[0], rgb[1], rgb[2]);
+        }
+        true
+    }
+
+    /// The last complete frame, row by row.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.pixels
+    }
+}
+
+/// A TPM2.net packet: its type, packet number, packet count and payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetPacket {
+    pub packet_type: u8,
+    pub number: u8,
+    pub total: u8,
+    pub payload: Vec<u8>,
+}
+
+impl NetPacket {
+    pub fn parse(data: &[u8]) -> Result<NetPacket, &'static str> {
+        if data.len() < NET_HEADER_SIZE + 1 || data[0] != NET_START {
+            return Err("not a TPM2.net packet");
+        }
+        let size = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let end = NET_HEADER_SIZE + size;
+        if end >= data.len() || data[end] != END {
+            return Err("bad packet size");
+        }
+        Ok(NetPacket { packet_type: data[1], number: data[4], total: data[5], payload: data[NET_HEADER_SIZE..end].to_vec() })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![NET_START, self.packet_type];
+        bytes.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&[self.number, self.total]);
+        bytes.extend_from_slice(&self.payload);
+        bytes.push(END);
+        bytes
+    }
+}
+
+/// Splits a serial byte stream into the payloads of its data frames,
+/// skipping garbage between frames.
+pub struct SerialDecoder {
+    buffer: Vec<u8>,
+    max_payload: usize,
+}
+
+impl SerialDecoder {
+    /// Frames declaring more than `max_payload` bytes, normally three per
+    /// pixel, are taken for a stray start byte rather than waited for.
+    pub fn new(max_payload: usize) -> SerialDecoder {
+        SerialDecoder { buffer: Vec::new(), max_payload }
+    }
+
+    /// Feeds received bytes and returns the payloads of the data frames
+    /// they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(bytes);
+        let mut payloads = Vec::new();
+        loop {
+            // resynchronize on the next start byte
+            match self.buffer.iter().position(|byte| *byte == SERIAL_START) {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                }
+                None => {
+                    self.buffer.clear();
+                    break;
+                }
+            }
+            if self.buffer.len() < SERIAL_HEADER_SIZE {
+                break;
+            }
+
+            let size = u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as usize;
+            let end = SERIAL_HEADER_SIZE + size;
+            if size <= self.max_payload && self.buffer.len() <= end {
+                break;
+            }
+            if size > self.max_payload || self.buffer[end] != END {
+                // not a frame after all
+                self.buffer.drain(..1);
+                continue;
+            }
+            if self.buffer[1] == TYPE_DATA {
+                payloads.push(self.buffer[SERIAL_HEADER_SIZE..end].to_vec());
+            }
+            self.buffer.drain(..=end);
+        }
+        payloads
+    }
+}
+
+/// Frames a payload for sending over serial.
+pub fn serial_frame(packet_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![SERIAL_START, packet_type];
+    bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes.push(END);
+    bytes
+}
+
+/*
+ * TPM2.net
+ */
+
+pub struct NetReceiver {
+    socket: UdpSocket,
+    assembler: FrameAssembler,
+    buffer: Vec<u8>,
+}
+
+impl NetReceiver {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, for
+    /// frames of `width` by `height` pixels, normally `Canvas::get_size`.
+    pub fn bind<A: ToSocketAddrs>(address: A, width: i32, height: i32) -> io::Result<NetReceiver> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        Ok(NetReceiver { socket, assembler: FrameAssembler::new(width, height), buffer: vec![0; MAX_DATAGRAM] })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn pixels(&self) -> &[RGB8] {
+        self.assembler.pixels()
+    }
+
+    /// Waits briefly for a packet. Returns whether a frame completed.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let packet = match receive_datagram(&self.socket, &mut self.buffer)? {
+            Some((size, _)) => NetPacket::parse(&self.buffer[..size]),
+            None => return Ok(false),
+        };
+        Ok(match packet {
+            Ok(packet) if packet.packet_type == TYPE_DATA => {
+                self.assembler.add_packet(packet.number, packet.total, &packet.payload)
+            }
+            _ => false,
+        })
+    }
+
+    /// Shows the received frames on `matrix` until `stop` is used.
+    pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+        let mut presenter = Presenter::new(matrix);
+        while !stop.is_stopped() {
+            if self.receive()? {
+                presenter.present(matrix, self.assembler.pixels());
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Serial
+ */
+
+#[cfg(target_os = "linux")]
+pub use self::serial::SerialReceiver;
+
+#[cfg(target_os = "linux")]
+mod serial {
+    use super::{FrameAssembler, SerialDecoder};
+    use crate::animation::StopHandle;
+    use crate::matrix::Matrix;
+    use crate::net::Presenter;
+
+    use rgb::RGB8;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    fn baud_constant(baud: u32) -> Option<libc::speed_t> {
+        Some(match baud {
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            460800 => libc::B460800,
+            500000 => libc::B500000,
+            921600 => libc::B921600,
+            1000000 => libc::B1000000,
+            1500000 => libc::B1500000,
+            2000000 => libc::B2000000,
+            _ => return None,
+        })
+    }
+
+    /// Puts the tty in raw mode at `baud`, with reads giving up after a
+    /// tenth of a second so stop requests are noticed.
+    fn configure(port: &File, baud: u32) -> io::Result<()> {
+        let speed = baud_constant(baud)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unsupported baud rate"))?;
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(port.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 1;
+            if libc::cfsetspeed(&mut termios, speed) != 0
+                || libc::tcsetattr(port.as_raw_fd(), libc::TCSANOW, &termios) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub struct SerialReceiver {
+        port: File,
+        decoder: SerialDecoder,
+        assembler: FrameAssembler,
+        buffer: Vec<u8>,
+    }
+
+    impl SerialReceiver {
+        /// Opens a tty such as `/dev/ttyACM0` for frames of `width` by
+        /// `height` pixels, normally `Canvas::get_size`.
+        pub fn open(path: &Path, baud: u32, width: i32, height: i32) -> io::Result<SerialReceiver> {
+            let port = OpenOptions::new().read(true).write(true).open(path)?;
+            configure(&port, baud)?;
+            Ok(SerialReceiver::from_file(port, width, height))
+        }
+
+        /// Reads TPM2 from an already set up file or pipe.
+        pub fn from_file(port: File, width: i32, height: i32) -> SerialReceiver {
+            SerialReceiver {
+                port,
+                decoder: SerialDecoder::new((width * height).max(0) as usize * 3),
+                assembler: FrameAssembler::new(width, height),
+                buffer: vec![0; 4096],
+            }
+        }
+
+        pub fn pixels(&self) -> &[RGB8] {
+            self.assembler.pixels()
+        }
+
+        /// Reads what arrived within the port's read timeout. Returns
+        /// whether a frame completed.
+        pub fn receive(&mut self) -> io::Result<bool> {
+            let read = match self.port.read(&mut self.buffer) {
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            let mut complete = false;
+            for payload in self.decoder.push(&self.buffer[..read]) {
+                complete |= self.assembler.add_packet(1, 1, &payload);
+            }
+            Ok(complete)
+        }
+
+        /// Shows the received frames on `matrix` until `stop` is used.
+        pub fn run(&mut self, matrix: &mut Matrix, stop: &StopHandle) -> io::Result<()> {
+            let mut presenter = Presenter::new(matrix);
+            while !stop.is_stopped() {
+                if self.receive()? {
+                    presenter.present(matrix, self.assembler.pixels());
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reassembles_split_frames() {
+        let mut assembler = FrameAssembler::new(2, 1);
+        assert!(!assembler.add_packet(2, 2, &[4, 5, 6]));
+        assert!(assembler.add_packet(1, 2, &[1, 2, 3]));
+        assert_eq!(assembler.pixels(), &[RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)]);
+        assert!(!assembler.add_packet(3, 2, &[0, 0, 0]));
+    }
+
+    #[test]
+    fn decodes_serial_stream() {
+        let mut stream = vec![0x00, 0x36];
+        stream.extend(serial_frame(TYPE_DATA, &[1, 2, 3]));
+        stream.extend(serial_frame(TYPE_COMMAND, &[9]));
+        stream.extend(serial_frame(TYPE_DATA, &[4, 5, 6]));
+
+        let mut decoder = SerialDecoder::new(3);
+        let (first, rest) = stream.split_at(5);
+        assert!(decoder.push(first).is_empty());
+        assert_eq!(decoder.push(rest), vec![vec![1, 2, 3], vec![4, 5, 6]]);
+
+        // a stray start byte claiming a huge frame doesn't hold up the next one
+        let mut stream = vec![SERIAL_START, TYPE_DATA, 0xff, 0xff];
+        stream.extend(serial_frame(TYPE_DATA, &[7, 8, 9]));
+        assert_eq!(decoder.push(&stream), vec![vec![7, 8, 9]]);
+    }
+
+    #[test]
+    fn receives_over_udp() {
+        let mut receiver = NetReceiver::bind("127.0.0.1:0", 2, 1).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for (number, payload) in [(1, vec![1, 2, 3]), (2, vec![4, 5, 6])] {
+            let packet = NetPacket { packet_type: TYPE_DATA, number, total: 2, payload };
+            assert_eq!(NetPacket::parse(&packet.to_bytes()), Ok(packet.clone()));
+            sender.send_to(&packet.to_bytes(), receiver.local_addr().unwrap()).unwrap();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !receiver.receive().unwrap() {
+            assert!(Instant::now() < deadline, "no frame arrived");
+        }
+        assert_eq!(receiver.pixels(), &[RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)]);
+    }
+}
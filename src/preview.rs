@@ -0,0 +1,319 @@
+//! Watch the matrix from a browser.
+//!
+//! `PreviewServer` serves a small viewer page at `/` that connects back over
+//! a WebSocket and draws every frame as round LEDs. Hand its `Publisher` to
+//! `Matrix::set_preview` and each `swap_canvas_on_vsync` offers the frame
+//! shown; a copy of it is all the swapping thread pays. Viewers get the
+//! newest frame at most at the server's frame rate, skipping the ones in
+//! between, so the last frame shown always arrives. Compression and
+//! sending happen on one thread per viewer.
+//!
+//! Frames go out as binary messages: the width and height as big endian
+//! u16s, then the RGB pixels row by row, zlib compressed.
+
+use super::animation::{StopHandle, STOP_POLL_INTERVAL};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rgb::RGB8;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+pub const DEFAULT_PORT: u16 = 8008;
+pub const DEFAULT_FPS: f32 = 15.0;
+
+const VIEWER: &str = include_str!("preview.html");
+const MAX_REQUEST_HEAD: usize = 4096;
+/// How long a client gets to send its request, or to take a frame.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * Frames
+ */
+
+/// Packs pixels into a preview message.
+pub fn encode_frame(pixels: &[RGB8], width: i32, height: i32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pixels.len() / 4);
+    out.extend_from_slice(&(width as u16).to_be_bytes());
+    out.extend_from_slice(&(height as u16).to_be_bytes());
+    let mut encoder = ZlibEncoder::new(out, Compression::fast());
+    for pixel in pixels {
+        // writing to a Vec can't fail
+        let _ = encoder.write_all(&[pixel.r, pixel.g, pixel.b]);
+    }
+    encoder.finish().unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Frame {
+    /// Counts published frames, 0 being none yet.
+    sequence: u64,
+    width: i32,
+    height: i32,
+    /// Shared so viewers can take it without copying under the lock.
+    pixels: Arc<Vec<RGB8>>,
+    /// The message for this frame, once a viewer made it.
+    encoded: Option<Arc<Vec<u8>>>,
+}
+
+struct Shared {
+    frame: Mutex<Frame>,
+    changed: Condvar,
+    interval: Duration,
+}
+
+/// Offers frames to a `PreviewServer`, see `Matrix::set_preview`.
+#[derive(Clone)]
+pub struct Publisher {
+    shared: Arc<Shared>,
+}
+
+impl Publisher {
+    /// Keeps a copy of the frame for the viewers, replacing the previous
+    /// one. The lock is only held to swap in the copy, which viewers never
+    /// hold longer either.
+    pub fn publish(&self, pixels: &[RGB8], width: i32, height: i32) {
+        let pixels = Arc::new(pixels.to_vec());
+        let mut frame = self.shared.frame.lock().unwrap();
+        frame.sequence += 1;
+        frame.width = width;
+        frame.height = height;
+        frame.pixels = pixels;
+        frame.encoded = None;
+        self.shared.changed.notify_all();
+    }
+
+    /// Waits for a frame newer than `seen` and returns it with its
+    /// sequence, or `None` once `stop` is used.
+    fn next_frame(&self, seen: u64, stop: &StopHandle) -> Option<(u64, Arc<Vec<u8>>)> {
+        let mut frame = self.shared.frame.lock().unwrap();
+        while frame.sequence == seen {
+            if stop.is_stopped() {
+                return None;
+            }
+            frame = self.shared.changed.wait_timeout(frame, STOP_POLL_INTERVAL).unwrap().0;
+        }
+        if let Some(encoded) = &frame.encoded {
+            return Some((frame.sequence, encoded.clone()));
+        }
+
+        // compress without holding the lock, so publishing never waits on it
+        let (sequence, width, height, pixels) = (frame.sequence, frame.width, frame.height, Arc::clone(&frame.pixels));
+        drop(frame);
+        let encoded = Arc::new(encode_frame(&pixels, width, height));
+        let mut frame = self.shared.frame.lock().unwrap();
+        if frame.sequence == sequence {
+            frame.encoded = Some(encoded.clone());
+        }
+        Some((sequence, encoded))
+    }
+}
+
+/*
+ * Server
+ */
+
+pub struct PreviewServer {
+    local_addr: SocketAddr,
+    publisher: Publisher,
+    stop: StopHandle,
+}
+
+/// Whether the peeked request asks for a WebSocket. Waits for the whole
+/// request head, or as much of it as fits.
+fn is_upgrade(stream: &TcpStream) -> io::Result<bool> {
+    let mut head = [0; MAX_REQUEST_HEAD];
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    loop {
+        let size = stream.peek(&mut head)?;
+        let text = String::from_utf8_lossy(&head[..size]).to_ascii_lowercase();
+        if text.contains("\r\n\r\n") || size == head.len() {
+            return Ok(text.contains("upgrade: websocket"));
+        }
+        if size == 0 || Instant::now() > deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "incomplete request"));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Answers a plain HTTP request with the viewer page.
+fn serve_page(mut stream: TcpStream) -> io::Result<()> {
+    let mut head = [0; MAX_REQUEST_HEAD];
+    let size = stream.read(&mut head)?;
+    let request = String::from_utf8_lossy(&head[..size]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/" | "/index.html" => ("200 OK", VIEWER),
+        _ => ("404 Not Found", "not found\n"),
+    };
+    let content_type = if body == VIEWER { "text/html; charset=utf-8" } else { "text/plain" };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Sends frames to one viewer until it goes away or `stop` is used.
+fn serve_viewer(stream: TcpStream, publisher: &Publisher, stop: &StopHandle) -> io::Result<()> {
+    let to_io = |err: tungstenite::Error| io::Error::other(err.to_string());
+    let mut socket = tungstenite::accept(stream).map_err(|err| io::Error::other(err.to_string()))?;
+    let mut seen = 0;
+    let mut sent: Option<Instant> = None;
+    loop {
+        // pause before picking the frame, so the one sent is the newest
+        if let Some(sent) = sent {
+            let due = sent + publisher.shared.interval;
+            while !stop.is_stopped() {
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+                thread::sleep((due - now).min(STOP_POLL_INTERVAL));
+            }
+        }
+        let (sequence, encoded) = match publisher.next_frame(seen, stop) {
+            Some(next) => next,
+            None => break,
+        };
+        socket.send(Message::Binary(encoded.to_vec())).map_err(to_io)?;
+        sent = Some(Instant::now());
+        seen = sequence;
+    }
+    socket.close(None).map_err(to_io)
+}
+
+fn serve(stream: TcpStream, publisher: &Publisher, stop: &StopHandle) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    if is_upgrade(&stream)? {
+        serve_viewer(stream, publisher, stop)
+    } else {
+        serve_page(stream)
+    }
+}
+
+impl PreviewServer {
+    /// Listens on `address`, usually `("0.0.0.0", DEFAULT_PORT)`, sending
+    /// viewers at most `max_fps` frames a second.
+    pub fn bind<A: ToSocketAddrs>(address: A, max_fps: f32) -> io::Result<PreviewServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let publisher = Publisher {
+            shared: Arc::new(Shared {
+                frame: Mutex::new(Frame::default()),
+                changed: Condvar::new(),
+                interval: Duration::from_secs_f32(1.0 / max_fps.max(0.1)),
+            }),
+        };
+        let stop = StopHandle::new();
+
+        let (accepting, accept_stop) = (publisher.clone(), stop.clone());
+        thread::spawn(move || {
+            while !accept_stop.is_stopped() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let (publisher, stop) = (accepting.clone(), accept_stop.clone());
+                        thread::spawn(move || serve(stream, &publisher, &stop));
+                    }
+                    Err(_) => thread::sleep(STOP_POLL_INTERVAL),
+                }
+            }
+        });
+
+        Ok(PreviewServer { local_addr, publisher, stop })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Where frames for the viewers go, see `Matrix::set_preview`.
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+}
+
+impl Drop for PreviewServer {
+    fn drop(&mut self) {
+        self.stop.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+
+    fn decode(message: &[u8]) -> (u16, u16, Vec<u8>) {
+        let mut rgb = Vec::new();
+        ZlibDecoder::new(&message[4..]).read_to_end(&mut rgb).unwrap();
+        (u16::from_be_bytes([message[0], message[1]]), u16::from_be_bytes([message[2], message[3]]), rgb)
+    }
+
+    #[test]
+    fn keeps_the_newest_frame() {
+        let server = PreviewServer::bind("127.0.0.1:0", 1.0).unwrap();
+        let publisher = server.publisher();
+        publisher.publish(&[RGB8::new(1, 2, 3)], 1, 1);
+        publisher.publish(&[RGB8::new(4, 5, 6)], 1, 1);
+
+        let (sequence, encoded) = publisher.next_frame(0, &server.stop).unwrap();
+        assert_eq!(sequence, 2);
+        assert_eq!(decode(&encoded), (1, 1, vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn delivers_frames_published_within_the_interval() {
+        let server = PreviewServer::bind("127.0.0.1:0", 10.0).unwrap();
+        let address = server.local_addr();
+        let url = format!("ws://{}/ws", address);
+        let (mut socket, _) = tungstenite::client(url, TcpStream::connect(address).unwrap()).unwrap();
+
+        let publisher = server.publisher();
+        publisher.publish(&[RGB8::new(1, 2, 3)], 1, 1);
+        let mut received = Vec::new();
+        while received.last() != Some(&vec![4, 5, 6]) {
+            match socket.read().unwrap() {
+                Message::Binary(message) => received.push(decode(&message).2),
+                other => panic!("unexpected {:?}", other),
+            }
+            if received.len() == 1 {
+                // right after the first, well inside the 100ms interval
+                publisher.publish(&[RGB8::new(4, 5, 6)], 1, 1);
+            }
+        }
+        assert_eq!(received, vec![vec![1, 2, 3], vec![4, 5, 6]]);
+    }
+
+    #[test]
+    fn streams_to_viewers() {
+        let server = PreviewServer::bind("127.0.0.1:0", 1000.0).unwrap();
+        let address = server.local_addr();
+
+        let mut page = TcpStream::connect(address).unwrap();
+        page.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        page.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200") && response.contains("DecompressionStream"));
+
+        let url = format!("ws://{}/ws", address);
+        let (mut socket, _) = tungstenite::client(url, TcpStream::connect(address).unwrap()).unwrap();
+        let pixels = [RGB8::new(255, 0, 0), RGB8::new(0, 0, 255)];
+        server.publisher().publish(&pixels, 2, 1);
+        match socket.read().unwrap() {
+            Message::Binary(message) => assert_eq!(decode(&message), (2, 1, vec![255, 0, 0, 0, 0, 255])),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
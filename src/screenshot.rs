@@ -0,0 +1,152 @@
+//! Saving what is drawn on a canvas as PNG or PPM files.
+
+use super::image::Image;
+
+use rgb::{RGB8, RGBA8};
+use std::io::{self, Write};
+
+/// Samples per axis when working out how much of an output pixel a round
+/// LED covers.
+const SUPERSAMPLING: i32 = 4;
+/// Largest `scale`; a 32 times bigger screenshot is already huge.
+pub const MAX_SCALE: i32 = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LedShape {
+    /// Every LED is a solid square, the usual pixel art look.
+    Square,
+    /// LEDs are discs on black, like the physical panel. Needs a `scale`
+    /// of 3 or more to look like anything.
+    Round,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScreenshotOptions {
+    /// Output pixels per LED along each axis, 1 to `MAX_SCALE`.
+    pub scale: i32,
+    pub shape: LedShape,
+}
+
+impl ScreenshotOptions {
+    pub fn new(scale: i32, shape: LedShape) -> ScreenshotOptions {
+        ScreenshotOptions { scale: scale.clamp(1, MAX_SCALE), shape }
+    }
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> ScreenshotOptions {
+        ScreenshotOptions::new(1, LedShape::Square)
+    }
+}
+
+/// Renders `width` by `height` LEDs, row by row, into an image. LEDs past
+/// the end of `pixels` are black.
+pub fn render(pixels: &[RGB8], width: i32, height: i32, options: &ScreenshotOptions) -> Image {
+    let (width, height) = (width.max(0), height.max(0));
+    let scale = options.scale.clamp(1, MAX_SCALE);
+    let coverage = led_coverage(scale, options.shape);
+
+    let mut out = vec![RGBA8::new(0, 0, 0, 255); (width * scale * height * scale) as usize];
+    for y in 0..height * scale {
+        for x in 0..width * scale {
+            let led = pixels.get(((y / scale) * width + x / scale) as usize).copied().unwrap_or_default();
+            let cover = coverage[((y % scale) * scale + x % scale) as usize] as u32;
+            let dim = |channel: u8| ((channel as u32 * cover + 127) / 255) as u8;
+            out[(y * width * scale + x) as usize] = RGBA8::new(dim(led.r), dim(led.g), dim(led.b), 255);
+        }
+    }
+
+    Image::new(width * scale, height * scale, out)
+}
+
+/// How much of each output pixel within one LED's `scale` by `scale` cell
+/// is lit, from 0 to 255.
+fn led_coverage(scale: i32, shape: LedShape) -> Vec<u8> {
+    if shape == LedShape::Square {
+        return vec![255; (scale * scale) as usize];
+    }
+
+    // leave a dark gap between neighbouring LEDs, as on the panel
+    let center = scale as f32 / 2.0;
+    let radius = scale as f32 * 0.42;
+    let samples = SUPERSAMPLING * SUPERSAMPLING;
+    let mut coverage = Vec::with_capacity((scale * scale) as usize);
+    for y in 0..scale {
+        for x in 0..scale {
+            let mut inside = 0;
+            for sy in 0..SUPERSAMPLING {
+                for sx in 0..SUPERSAMPLING {
+                    let px = x as f32 + (sx as f32 + 0.5) / SUPERSAMPLING as f32 - center;
+                    let py = y as f32 + (sy as f32 + 0.5) / SUPERSAMPLING as f32 - center;
+                    if px * px + py * py <= radius * radius {
+                        inside += 1;
+                    }
+                }
+            }
+            coverage.push((inside * 255 / samples) as u8);
+        }
+    }
+    coverage
+}
+
+/// Writes `image` as a binary PPM (P6), dropping the alpha channel.
+pub fn write_ppm<W: Write>(image: &Image, out: &mut W) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", image.width(), image.height())?;
+    let data: Vec<u8> = image.pixels().iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]).collect();
+    out.write_all(&data)
+}
+
+/// Writes `image` as an 8 bit RGB PNG.
+#[cfg(feature = "png")]
+pub fn write_png<W: Write>(image: &Image, out: W) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, image.width() as u32, image.height() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = image.pixels().iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]).collect();
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+#[cfg(feature = "png")]
+fn png_error(err: png::EncodingError) -> io::Error {
+    match err {
+        png::EncodingError::IoError(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upscales_square_leds() {
+        let red = RGB8::new(255, 0, 0);
+        let image = render(&[red, RGB8::default()], 2, 1, &ScreenshotOptions::new(3, LedShape::Square));
+        assert_eq!((image.width(), image.height()), (6, 3));
+        assert_eq!(image.pixel(2, 2), RGBA8::new(255, 0, 0, 255));
+        assert_eq!(image.pixel(3, 0), RGBA8::new(0, 0, 0, 255));
+
+        let huge = ScreenshotOptions { scale: i32::MAX, shape: LedShape::Square };
+        assert_eq!(render(&[red], 1, 1, &huge).width(), MAX_SCALE);
+        assert_eq!(ScreenshotOptions::new(1000, LedShape::Square).scale, MAX_SCALE);
+    }
+
+    #[test]
+    fn round_leds_have_dark_corners() {
+        let white = RGB8::new(255, 255, 255);
+        let image = render(&[white], 1, 1, &ScreenshotOptions::new(8, LedShape::Round));
+        assert_eq!(image.pixel(0, 0), RGBA8::new(0, 0, 0, 255));
+        assert_eq!(image.pixel(4, 4), RGBA8::new(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn ppm_header() {
+        let image = render(&[RGB8::new(1, 2, 3)], 1, 1, &ScreenshotOptions::default());
+        let mut out = Vec::new();
+        write_ppm(&image, &mut out).unwrap();
+        assert_eq!(out, b"P6\n1 1\n255\n\x01\x02\x03");
+    }
+}
@@ -0,0 +1,300 @@
+//! Reading and writing the `.stream` files of the C library's
+//! `content-streamer`, as replayed by `led-image-viewer`.
+//!
+//! A stream is a file header followed by frames, each a frame header and
+//! the canvas in the library's internal PWM bitplane format. All headers
+//! are 32 bytes of little endian fields. Frames are only valid for the
+//! exact matrix configuration (size, chain, parallel, PWM bits) that
+//! recorded them.
+
+use super::animation::{StopHandle, STOP_POLL_INTERVAL};
+use super::canvas::Canvas;
+use super::matrix::Matrix;
+
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Marks the file header, as `kFileMagicValue` in content-streamer.cc.
+const FILE_MAGIC: u32 = 0xed0c_5d1a;
+/// Marks every frame header, as `kFrameMagicValue`.
+const FRAME_MAGIC: u32 = 0x1234_5678;
+const HEADER_SIZE: usize = 32;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/*
+ * Headers
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// Size of the canvas the stream was recorded from.
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per frame; every frame of a stream has the same size.
+    pub frame_size: u32,
+    /// Recorded by a library built with 64 bit GPIO words (Compute Modules).
+    pub wide_64bit: bool,
+}
+
+impl StreamHeader {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&FILE_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.frame_size.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.width.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.height.to_le_bytes());
+        // bytes 16..24 are reserved, then a bitfield whose lowest bit is the only one in use
+        bytes[24] = self.wide_64bit as u8;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> io::Result<StreamHeader> {
+        if u32_at(bytes, 0) != FILE_MAGIC {
+            return Err(invalid_data("not a content stream"));
+        }
+        Ok(StreamHeader {
+            frame_size: u32_at(bytes, 4),
+            width: u32_at(bytes, 8),
+            height: u32_at(bytes, 12),
+            wide_64bit: bytes[24] & 1 != 0,
+        })
+    }
+}
+
+/// One recorded frame and how long it stays on the matrix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamFrame {
+    pub data: Vec<u8>,
+    pub hold_time: Duration,
+}
+
+/*
+ * Writer
+ */
+
+pub struct StreamWriter<W: Write> {
+    out: W,
+    header: Option<StreamHeader>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// The file header is written along with the first frame.
+    pub fn new(out: W) -> StreamWriter<W> {
+        StreamWriter { out, header: None }
+    }
+
+    /// Appends the current contents of `canvas`, to be shown for `hold_time`.
+    /// Canvases of a remote matrix have no C library buffer to record.
+    pub fn write_canvas(&mut self, canvas: &Canvas, hold_time: Duration) -> io::Result<()> {
+        if canvas.is_detached() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "canvas has no buffer to record"));
+        }
+        let (width, height) = canvas.get_size();
+        self.write_frame(width as u32, height as u32, &canvas.serialize(), hold_time)
+    }
+
+    /// Appends an already serialized frame.
+    pub fn write_frame(&mut self, width: u32, height: u32, data: &[u8], hold_time: Duration) -> io::Result<()> {
+        match self.header {
+            None => {
+                let header = StreamHeader { width, height, frame_size: data.len() as u32, wide_64bit: false };
+                self.out.write_all(&header.to_bytes())?;
+                self.header = Some(header);
+            }
+            Some(header) => {
+                if header.width != width || header.height != height || header.frame_size as usize != data.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame doesn't match the stream"));
+                }
+            }
+        }
+
+        let mut frame_header = [0; HEADER_SIZE];
+        frame_header[0..4].copy_from_slice(&FRAME_MAGIC.to_le_bytes());
+        frame_header[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        let hold_us = hold_time.as_micros().min(u32::MAX as u128) as u32;
+        frame_header[8..12].copy_from_slice(&hold_us.to_le_bytes());
+        self.out.write_all(&frame_header)?;
+        self.out.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/*
+ * Reader
+ */
+
+pub struct StreamReader<R: Read> {
+    input: R,
+    header: StreamHeader,
+}
+
+impl<R: Read> StreamReader<R> {
+    /// Reads and checks the file header.
+    pub fn new(mut input: R) -> io::Result<StreamReader<R>> {
+        let mut bytes = [0; HEADER_SIZE];
+        input.read_exact(&mut bytes)?;
+        let header = StreamHeader::from_bytes(&bytes)?;
+        Ok(StreamReader { input, header })
+    }
+
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+
+    /// The next frame, or `None` at the end of the stream.
+    pub fn next_frame(&mut self) -> io::Result<Option<StreamFrame>> {
+        let mut bytes = [0; HEADER_SIZE];
+        match self.input.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if u32_at(&bytes, 0) != FRAME_MAGIC || u32_at(&bytes, 4) != self.header.frame_size {
+            return Err(invalid_data("corrupt frame header"));
+        }
+
+        // grows with what is actually there, so a corrupt size can't make
+        // us allocate gigabytes up front
+        let mut data = Vec::new();
+        let size = self.header.frame_size as u64;
+        if self.input.by_ref().take(size).read_to_end(&mut data)? as u64 != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"));
+        }
+        Ok(Some(StreamFrame {
+            data,
+            hold_time: Duration::from_micros(u32_at(&bytes, 8) as u64),
+        }))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+/*
+ * Playback
+ */
+
+/// Shows every frame of `reader` on `matrix` for its recorded time, until
+/// the stream ends or `stop` is used. Returns the number of frames shown.
+pub fn play<R: Read>(matrix: &mut Matrix, reader: &mut StreamReader<R>, stop: &StopHandle) -> io::Result<usize> {
+    let mut displayed = matrix.get_canvas();
+    let mut offscreen = matrix.create_offscreen_canvas();
+    let (width, height) = offscreen.get_size();
+    if (width as u32, height as u32) != (reader.header.width, reader.header.height) {
+        return Err(invalid_data("stream was recorded for a different canvas size"));
+    }
+
+    let mut shown = 0;
+    let mut deadline = Instant::now();
+    while !stop.is_stopped() {
+        let frame = match reader.next_frame()? {
+            Some(frame) => frame,
+            None => break,
+        };
+        if !offscreen.deserialize(&frame.data) {
+            return Err(invalid_data("stream was recorded for a different matrix configuration"));
+        }
+
+        matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+        std::mem::swap(&mut offscreen, &mut displayed);
+        shown += 1;
+
+        deadline = (deadline + frame.hold_time).max(Instant::now());
+        while !stop.is_stopped() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            sleep((deadline - now).min(STOP_POLL_INTERVAL));
+        }
+    }
+
+    Ok(shown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_frame(64, 32, &[1, 2, 3, 4], Duration::from_millis(40)).unwrap();
+        writer.write_frame(64, 32, &[5, 6, 7, 8], Duration::from_micros(1500)).unwrap();
+        assert!(writer.write_frame(64, 32, &[1, 2], Duration::from_millis(1)).is_err());
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 3 * HEADER_SIZE + 8);
+        assert_eq!(&bytes[0..4], &[0x1a, 0x5d, 0x0c, 0xed]);
+
+        let mut reader = StreamReader::new(&bytes[..]).unwrap();
+        assert_eq!(*reader.header(), StreamHeader { width: 64, height: 32, frame_size: 4, wide_64bit: false });
+        let first = reader.next_frame().unwrap().unwrap();
+        assert_eq!(first, StreamFrame { data: vec![1, 2, 3, 4], hold_time: Duration::from_millis(40) });
+        assert_eq!(reader.next_frame().unwrap().unwrap().hold_time, Duration::from_micros(1500));
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_upstream_layout() {
+        // a file header and one frame as written by content-streamer.cc
+        #[rustfmt::skip]
+        let bytes = [
+            0x1a, 0x5d, 0x0c, 0xed, 2, 0, 0, 0, 64, 0, 0, 0, 32, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+            0x78, 0x56, 0x34, 0x12, 2, 0, 0, 0, 0x10, 0x27, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0xaa, 0xbb,
+        ];
+        let mut reader = StreamReader::new(&bytes[..]).unwrap();
+        assert_eq!(*reader.header(), StreamHeader { width: 64, height: 32, frame_size: 2, wide_64bit: true });
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame, StreamFrame { data: vec![0xaa, 0xbb], hold_time: Duration::from_millis(10) });
+
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_frame(64, 32, &[0xaa, 0xbb], Duration::from_millis(10)).unwrap();
+        let written = writer.into_inner();
+        assert_eq!(&written[..24], &bytes[..24]);
+        assert_eq!(&written[32..], &bytes[32..]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(StreamReader::new(&[0u8; 40][..]).is_err());
+        assert!(StreamReader::new(&[0u8; 4][..]).is_err());
+
+        // a header claiming 4 GiB frames, followed by a short frame
+        let mut bytes = StreamHeader { width: 1, height: 1, frame_size: u32::MAX, wide_64bit: false }.to_bytes().to_vec();
+        bytes.extend_from_slice(&FRAME_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; HEADER_SIZE - 8]);
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut reader = StreamReader::new(&bytes[..]).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn refuses_detached_canvases() {
+        let mut writer = StreamWriter::new(Vec::new());
+        let canvas = Canvas::detached(2, 1, &[]);
+        assert!(writer.write_canvas(&canvas, Duration::from_millis(40)).is_err());
+        assert!(writer.into_inner().is_empty());
+    }
+}
@@ -0,0 +1,493 @@
+use super::canvas::{PixelLocation, Rect};
+use super::font::{Font, TextMetrics};
+
+use rgb::RGB8;
+use std::time::Duration;
+
+/*
+ * Text Style
+ */
+
+/// How text is colored. Layers are painted back to front: background box,
+/// shadow, outline and finally the glyphs themselves.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TextStyle {
+    pub color: RGB8,
+    /// Fills each character cell, a full line of the font tall.
+    pub background: Option<RGB8>,
+    /// Color and width in pixels of a border traced around each glyph.
+    pub outline: Option<(RGB8, i32)>,
+    /// Offset and color of a copy of the glyphs drawn underneath.
+    pub shadow: Option<(PixelLocation, RGB8)>,
+}
+
+impl TextStyle {
+    pub fn new(color: RGB8) -> TextStyle {
+        TextStyle {
+            color,
+            background: None,
+            outline: None,
+            shadow: None,
+        }
+    }
+
+    /// The glyph layers to paint in order, as offsets from the text origin.
+    pub(crate) fn layers(&self) -> Vec<(PixelLocation, RGB8)> {
+        let mut layers = Vec::new();
+        if let Some((offset, color)) = self.shadow {
+            layers.push((offset, color));
+        }
+        if let Some((color, width)) = self.outline {
+            for dy in -width..=width {
+                for dx in -width..=width {
+                    if dx != 0 || dy != 0 {
+                        layers.push((PixelLocation { x: dx, y: dy }, color));
+                    }
+                }
+            }
+        }
+        layers.push((PixelLocation { x: 0, y: 0 }, self.color));
+        layers
+    }
+}
+
+impl From<RGB8> for TextStyle {
+    fn from(color: RGB8) -> TextStyle {
+        TextStyle::new(color)
+    }
+}
+
+/*
+ * Alignment
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HorizontalAlign {
+    Left,
+    Center,
+    Right,
+    /// Stretches the gaps between words so lines fill the box. The last
+    /// line of each paragraph stays left aligned.
+    Justify,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VerticalAlign {
+    Top,
+    Middle,
+    Bottom,
+}
+
+/*
+ * Text Box
+ */
+
+/// A run of text and the baseline position it should be drawn at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PositionedText {
+    pub origin: PixelLocation,
+    pub text: String,
+}
+
+/// Lays out a string inside a rectangle of the canvas.
+///
+/// Text is split into paragraphs on `\n`, each paragraph is wrapped
+/// greedily at spaces (or at any character if a single word is too wide),
+/// and whatever doesn't fit is cut off with `ellipsis`.
+pub struct TextBox<'a> {
+    pub font: &'a Font,
+    pub bounds: Rect,
+    pub align: HorizontalAlign,
+    pub vertical_align: VerticalAlign,
+    /// Extra pixels between lines; may be negative for tight fonts.
+    pub line_spacing: i32,
+    pub kerning_offset: i32,
+    pub wrap: bool,
+    /// Appended to the last visible line when text is cut off.
+    pub ellipsis: Option<&'a str>,
+}
+
+impl<'a> TextBox<'a> {
+    pub fn new(font: &'a Font, bounds: Rect) -> TextBox<'a> {
+        TextBox {
+            font,
+            bounds,
+            align: HorizontalAlign::Left,
+            vertical_align: VerticalAlign::Top,
+            line_spacing: 0,
+            kerning_offset: 0,
+            wrap: true,
+            ellipsis: Some("..."),
+        }
+    }
+
+    fn width_of(&self, text: &str) -> i32 {
+        self.font.measure(text, self.kerning_offset).width
+    }
+
+    /// Positions `utf8_text` within the box without drawing it. Each line
+    /// is one run, except justified lines which get one run per word.
+    pub fn layout(&self, utf8_text: &str) -> Vec<PositionedText> {
+        let line_height = self.font.height() + self.line_spacing;
+        if line_height <= 0 || self.bounds.height < self.font.height() {
+            return Vec::new();
+        }
+        let max_lines = ((self.bounds.height - self.font.height()) / line_height + 1) as usize;
+
+        // (words, ends a paragraph)
+        let mut lines: Vec<(Vec<String>, bool)> = Vec::new();
+        for paragraph in utf8_text.split('\n') {
+            let words: Vec<&str> = paragraph.split(' ').filter(|w| !w.is_empty()).collect();
+            if self.wrap {
+                let mut wrapped = self.wrap_words(&words);
+                if let Some(last) = wrapped.last_mut() {
+                    last.1 = true;
+                }
+                lines.append(&mut wrapped);
+            } else {
+                lines.push((words.iter().map(|w| w.to_string()).collect(), true));
+            }
+        }
+
+        let truncated = lines.len() > max_lines;
+        lines.truncate(max_lines);
+        let count = lines.len();
+
+        let block_height = count as i32 * line_height - self.line_spacing;
+        let top = match self.vertical_align {
+            VerticalAlign::Top => self.bounds.y,
+            VerticalAlign::Middle => self.bounds.y + (self.bounds.height - block_height) / 2,
+            VerticalAlign::Bottom => self.bounds.y + self.bounds.height - block_height,
+        };
+
+        let mut runs = Vec::new();
+        for (i, (words, paragraph_end)) in lines.into_iter().enumerate() {
+            let baseline = top + i as i32 * line_height + self.font.baseline();
+            let mut text = words.join(" ");
+            let cut = (truncated && i == count - 1) || self.width_of(&text) > self.bounds.width;
+            if cut {
+                text = self.ellipsize(&text);
+            }
+
+            if self.align == HorizontalAlign::Justify && !paragraph_end && !cut && words.len() > 1 {
+                self.justify(&words, baseline, &mut runs);
+                continue;
+            }
+
+            let slack = self.bounds.width - self.width_of(&text);
+            let x = match self.align {
+                HorizontalAlign::Left | HorizontalAlign::Justify => self.bounds.x,
+                HorizontalAlign::Center => self.bounds.x + slack / 2,
+                HorizontalAlign::Right => self.bounds.x + slack,
+            };
+            runs.push(PositionedText { origin: PixelLocation { x, y: baseline }, text });
+        }
+
+        runs
+    }
+
+    /// Greedy line breaking of a single paragraph.
+    fn wrap_words(&self, words: &[&str]) -> Vec<(Vec<String>, bool)> {
+        let space = self.width_of(" ");
+        let mut lines = Vec::new();
+        let mut line: Vec<String> = Vec::new();
+        let mut line_width = 0;
+
+        for word in words {
+            let width = self.width_of(word);
+            if !line.is_empty() && line_width + space + width <= self.bounds.width {
+                line.push(word.to_string());
+                line_width += space + width;
+                continue;
+            }
+
+            if !line.is_empty() {
+                lines.push((std::mem::take(&mut line), false));
+            }
+
+            // words wider than the box are broken wherever they have to be
+            let mut rest = word.to_string();
+            while self.width_of(&rest) > self.bounds.width {
+                let first_char = rest.chars().next().map_or(1, char::len_utf8);
+                let split = self.fit_prefix(&rest, self.bounds.width).max(first_char);
+                if split >= rest.len() {
+                    break;
+                }
+                let tail = rest.split_off(split);
+                lines.push((vec![rest], false));
+                rest = tail;
+            }
+            line_width = self.width_of(&rest);
+            line.push(rest);
+        }
+
+        lines.push((line, false));
+        lines
+    }
+
+    /// Byte length of the longest prefix of `text` no wider than `width`.
+    fn fit_prefix(&self, text: &str, width: i32) -> usize {
+        let mut used = 0;
+        for (index, c) in text.char_indices() {
+            used += self.font.char_width(c) + self.kerning_offset;
+            if used > width {
+                return index;
+            }
+        }
+        text.len()
+    }
+
+    fn ellipsize(&self, text: &str) -> String {
+        let ellipsis = match self.ellipsis {
+            Some(ellipsis) => ellipsis,
+            None => return text[..self.fit_prefix(text, self.bounds.width)].to_string(),
+        };
+        let room = self.bounds.width - self.width_of(ellipsis);
+        if room < 0 {
+            // not even the ellipsis fits
+            return ellipsis[..self.fit_prefix(ellipsis, self.bounds.width)].to_string();
+        }
+        let prefix = &text[..self.fit_prefix(text, room)];
+        format!("{}{}", prefix.trim_end(), ellipsis)
+    }
+
+    fn justify(&self, words: &[String], baseline: i32, runs: &mut Vec<PositionedText>) {
+        let words_width: i32 = words.iter().map(|w| self.width_of(w)).sum();
+        let gaps = words.len() as i32 - 1;
+        let slack = self.bounds.width - words_width;
+
+        let mut x = self.bounds.x;
+        for (i, word) in words.iter().enumerate() {
+            runs.push(PositionedText { origin: PixelLocation { x, y: baseline }, text: word.clone() });
+            // spread the remainder over the first gaps so the line ends flush
+            let i = i as i32;
+            x += self.width_of(word) + slack / gaps + if i < slack % gaps { 1 } else { 0 };
+        }
+    }
+}
+
+/*
+ * Scrolling
+ */
+
+/// How often the http and mqtt controllers redraw scrolling text.
+#[cfg(any(feature = "http", feature = "mqtt"))]
+pub(crate) const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Where to draw one line of `text` on a `width` by `height` canvas,
+/// vertically centered. With a `speed` in pixels per second it enters from
+/// the right and scrolls left, starting over once gone; with none it is
+/// centered.
+#[cfg(any(feature = "http", feature = "mqtt"))]
+pub(crate) fn marquee_origin(
+    font: &Font,
+    text: &str,
+    (width, height): (i32, i32),
+    speed: f32,
+    elapsed: Duration,
+) -> PixelLocation {
+    let text_width = font.measure(text, 0).width;
+    let x = if speed > 0.0 {
+        let travelled = (elapsed.as_secs_f32() * speed) as i32;
+        width - travelled % (width + text_width).max(1)
+    } else {
+        (width - text_width).max(0) / 2
+    };
+    PixelLocation { x, y: (height - font.height()) / 2 + font.baseline() }
+}
+
+/*
+ * Rich Text
+ */
+
+/// A piece of rich text sharing one style.
+#[derive(Clone)]
+pub struct Span<'a> {
+    pub text: String,
+    pub style: TextStyle,
+    /// Overrides the font of the enclosing `RichText`.
+    pub font: Option<&'a Font>,
+    /// Half period of blinking: the span is shown for this long, then
+    /// hidden for this long. Hidden spans still take up their space.
+    pub blink: Option<Duration>,
+}
+
+impl<'a> Span<'a> {
+    pub fn new(text: &str, style: TextStyle) -> Span<'a> {
+        Span {
+            text: text.to_string(),
+            style,
+            font: None,
+            blink: None,
+        }
+    }
+
+    /// Whether the span is in the "on" phase of its blink at `elapsed`.
+    pub fn is_visible(&self, elapsed: Duration) -> bool {
+        match self.blink {
+            Some(half_period) if half_period.as_nanos() > 0 => {
+                (elapsed.as_nanos() / half_period.as_nanos()) & 1 == 0
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A single line of text made of differently styled spans that share a
+/// baseline, e.g. route numbers in their line colors followed by a delay.
+pub struct RichText<'a> {
+    pub font: &'a Font,
+    pub spans: Vec<Span<'a>>,
+    pub kerning_offset: i32,
+}
+
+impl<'a> RichText<'a> {
+    pub fn new(font: &'a Font) -> RichText<'a> {
+        RichText {
+            font,
+            spans: Vec::new(),
+            kerning_offset: 0,
+        }
+    }
+
+    pub fn push(&mut self, span: Span<'a>) {
+        self.spans.push(span);
+    }
+
+    pub(crate) fn font_of(&self, span: &Span<'a>) -> &'a Font {
+        span.font.unwrap_or(self.font)
+    }
+
+    /// Measures all spans together, e.g. to know when a scroll wraps around.
+    pub fn measure(&self) -> TextMetrics {
+        let mut metrics = TextMetrics {
+            width: 0,
+            height: self.font.height(),
+            ascent: self.font.ascent(),
+            descent: self.font.descent(),
+        };
+
+        for span in &self.spans {
+            let span_metrics = self.font_of(span).measure(&span.text, self.kerning_offset);
+            metrics.width += span_metrics.width;
+            metrics.height = metrics.height.max(span_metrics.height);
+            metrics.ascent = metrics.ascent.max(span_metrics.ascent);
+            metrics.descent = metrics.descent.max(span_metrics.descent);
+        }
+        // spans share the baseline, so the tallest ascent and the deepest
+        // descent can come from different fonts
+        metrics.height = metrics.height.max(metrics.ascent + metrics.descent);
+
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::tests::TEST_BDF;
+
+    #[test]
+    fn style_layers() {
+        let white = RGB8::new(255, 255, 255);
+        let black = RGB8::new(0, 0, 0);
+        let grey = RGB8::new(64, 64, 64);
+        assert_eq!(TextStyle::new(white).layers(), vec![(PixelLocation { x: 0, y: 0 }, white)]);
+
+        let mut style = TextStyle::new(white);
+        style.outline = Some((black, 1));
+        style.shadow = Some((PixelLocation { x: 1, y: 1 }, grey));
+        let layers = style.layers();
+        assert_eq!(layers.len(), 10);
+        assert_eq!(layers[0], (PixelLocation { x: 1, y: 1 }, grey));
+        assert!(layers[1..9].iter().all(|&(_, color)| color == black));
+        assert_eq!(layers[9], (PixelLocation { x: 0, y: 0 }, white));
+    }
+
+    fn texts(runs: &[PositionedText]) -> Vec<&str> {
+        runs.iter().map(|run| run.text.as_str()).collect()
+    }
+
+    #[test]
+    fn rich_text() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let red = TextStyle::new(RGB8::new(255, 0, 0));
+        let mut rich = RichText::new(&font);
+        rich.kerning_offset = 1;
+        rich.push(Span::new("AA", red));
+        let mut delay = Span::new(" A.", red);
+        delay.blink = Some(Duration::from_millis(500));
+        rich.push(delay);
+
+        assert_eq!(rich.measure(), TextMetrics { width: 30, height: 8, ascent: 7, descent: 1 });
+        assert!(rich.spans[1].is_visible(Duration::from_millis(499)));
+        assert!(!rich.spans[1].is_visible(Duration::from_millis(500)));
+        assert!(rich.spans[1].is_visible(Duration::from_millis(1000)));
+        assert!(rich.spans[0].is_visible(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn rich_text_mixing_baselines() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let low = TEST_BDF
+            .replace("FONTBOUNDINGBOX 5 8 0 -1", "FONTBOUNDINGBOX 5 8 0 -4")
+            .replace("FONT_ASCENT 7", "FONT_ASCENT 4")
+            .replace("FONT_DESCENT 1", "FONT_DESCENT 4");
+        let low = Font::from_bytes(low.as_bytes()).unwrap();
+        let style = TextStyle::new(RGB8::new(255, 255, 255));
+        let mut rich = RichText::new(&font);
+        rich.push(Span::new("A", style));
+        let mut deep = Span::new("A", style);
+        deep.font = Some(&low);
+        rich.push(deep);
+
+        assert_eq!(rich.measure(), TextMetrics { width: 10, height: 11, ascent: 7, descent: 4 });
+    }
+
+    #[test]
+    fn wraps_and_aligns() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let mut text_box = TextBox::new(&font, Rect::new(0, 0, 20, 32));
+
+        let runs = text_box.layout("AA A\nA");
+        assert_eq!(texts(&runs), vec!["AA A", "A"]);
+        assert_eq!(runs[0].origin, PixelLocation { x: 0, y: 7 });
+        assert_eq!(runs[1].origin, PixelLocation { x: 0, y: 15 });
+
+        text_box.align = HorizontalAlign::Right;
+        text_box.vertical_align = VerticalAlign::Bottom;
+        let runs = text_box.layout("AAA AA");
+        assert_eq!(texts(&runs), vec!["AAA", "AA"]);
+        assert_eq!(runs[0].origin, PixelLocation { x: 5, y: 23 });
+        assert_eq!(runs[1].origin, PixelLocation { x: 10, y: 31 });
+
+        text_box.align = HorizontalAlign::Justify;
+        let runs = text_box.layout("A A AAAA");
+        assert_eq!(texts(&runs), vec!["A", "A", "AAAA"]);
+        assert_eq!(runs[1].origin.x, 15);
+    }
+
+    #[test]
+    fn breaks_long_words_and_ellipsizes() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let mut text_box = TextBox::new(&font, Rect::new(0, 0, 20, 16));
+
+        assert_eq!(texts(&text_box.layout("AAAAAA")), vec!["AAAA", "AA"]);
+        assert_eq!(texts(&text_box.layout("AAAAAAAAAAAA")), vec!["AAAA", "A..."]);
+
+        text_box.wrap = false;
+        text_box.ellipsis = None;
+        assert_eq!(texts(&text_box.layout("AAAAAA")), vec!["AAAA"]);
+    }
+
+    #[test]
+    fn trims_ellipsis_in_narrow_boxes() {
+        let font = Font::from_bytes(TEST_BDF.as_bytes()).unwrap();
+        let mut text_box = TextBox::new(&font, Rect::new(0, 0, 12, 8));
+        text_box.wrap = false;
+
+        assert_eq!(texts(&text_box.layout("AAAAAA")), vec![".."]);
+        text_box.bounds.width = 3;
+        assert_eq!(texts(&text_box.layout("AAAAAA")), vec![""]);
+    }
+}
@@ -0,0 +1,274 @@
+//! Video playback through an external decoder, normally ffmpeg, that
+//! writes raw RGB24 frames at the canvas size to its stdout.
+
+use super::animation::{StopHandle, STOP_POLL_INTERVAL};
+use super::canvas::{Canvas, PixelLocation};
+use super::matrix::Matrix;
+
+use rgb::RGB8;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/*
+ * Decoder
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoOptions {
+    /// Frames per second the decoder is asked for and playback is paced at.
+    pub fps: f64,
+    /// Start over at the end of the file instead of finishing.
+    pub looping: bool,
+    /// The ffmpeg executable; found through `PATH` by default.
+    pub ffmpeg: PathBuf,
+}
+
+impl VideoOptions {
+    pub fn new(fps: f64) -> VideoOptions {
+        VideoOptions { fps, looping: false, ffmpeg: PathBuf::from("ffmpeg") }
+    }
+}
+
+impl Default for VideoOptions {
+    fn default() -> VideoOptions {
+        VideoOptions::new(30.0)
+    }
+}
+
+/// A running decoder process. It is killed when dropped.
+pub struct Decoder {
+    child: Child,
+    stdout: ChildStdout,
+    width: i32,
+    height: i32,
+    frame: Vec<u8>,
+}
+
+impl Decoder {
+    /// Starts ffmpeg on `path`, letterboxing the video into `width` by `height`.
+    pub fn ffmpeg(path: &Path, width: i32, height: i32, options: &VideoOptions) -> io::Result<Decoder> {
+        let filter = format!(
+            "fps={fps},scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2",
+            fps = options.fps,
+            w = width,
+            h = height
+        );
+
+        let mut command = Command::new(&options.ffmpeg);
+        command.args(["-nostdin", "-loglevel", "error"]);
+        if options.looping {
+            command.args(["-stream_loop", "-1"]);
+        }
+        command.arg("-i").arg(path);
+        command.args(["-an", "-vf", &filter, "-f", "rawvideo", "-pix_fmt", "rgb24", "-"]);
+        Decoder::spawn(command, width, height)
+    }
+
+    /// Starts any process that writes `width` by `height` RGB24 frames,
+    /// back to back, to its stdout.
+    pub fn spawn(mut command: Command, width: i32, height: i32) -> io::Result<Decoder> {
+        let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Decoder { child, stdout, width, height, frame: vec![0; (width * height * 3) as usize] })
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// Reads the next frame as packed RGB24, or `None` once the decoder is done.
+    pub fn next_frame(&mut self) -> io::Result<Option<&[u8]>> {
+        let mut filled = 0;
+        while filled < self.frame.len() {
+            match self.stdout.read(&mut self.frame[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "decoder stopped mid-frame")),
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Some(&self.frame))
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Copies a packed RGB24 frame onto the top left corner of `canvas`.
+pub fn draw_rgb24(canvas: &mut Canvas, width: i32, data: &[u8]) {
+    for (index, pixel) in data.chunks_exact(3).enumerate() {
+        let location = PixelLocation { x: index as i32 % width, y: index as i32 / width };
+        canvas.set_pixel(&location, &RGB8::new(pixel[0], pixel[1], pixel[2]));
+    }
+}
+
+/*
+ * Pacing
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Pace {
+    /// Show the frame once this much time has passed.
+    Show(Duration),
+    /// The next frame is already due; skip this one to catch up.
+    Drop,
+}
+
+/// Schedules frames at a fixed rate from the start of playback, so a slow
+/// frame doesn't delay every later one.
+pub(crate) struct Pacer {
+    start: Instant,
+    period: Duration,
+}
+
+impl Pacer {
+    pub(crate) fn new(start: Instant, fps: f64) -> Pacer {
+        Pacer { start, period: Duration::from_secs_f64(1.0 / fps.max(0.001)) }
+    }
+
+    pub(crate) fn due(&self, frame: u32) -> Instant {
+        self.start + self.period * frame
+    }
+
+    pub(crate) fn pace(&self, frame: u32, now: Instant) -> Pace {
+        if now >= self.due(frame + 1) {
+            Pace::Drop
+        } else {
+            Pace::Show(self.due(frame).saturating_duration_since(now))
+        }
+    }
+}
+
+/*
+ * Player
+ */
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlaybackStats {
+    pub shown: usize,
+    /// Frames skipped because the decoder or the matrix fell behind.
+    pub dropped: usize,
+}
+
+/// Plays a video file on a matrix, double buffered through an offscreen
+/// canvas.
+pub struct VideoPlayer {
+    path: PathBuf,
+    pub options: VideoOptions,
+    stop: StopHandle,
+}
+
+impl VideoPlayer {
+    pub fn new(path: &Path, options: VideoOptions) -> VideoPlayer {
+        VideoPlayer { path: path.to_path_buf(), options, stop: StopHandle::new() }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Plays until the video ends or the stop handle is used.
+    pub fn play(&mut self, matrix: &mut Matrix) -> io::Result<PlaybackStats> {
+        let (width, height) = matrix.get_canvas().get_size();
+        let mut decoder = Decoder::ffmpeg(&self.path, width, height, &self.options)?;
+        play_decoder(matrix, &mut decoder, self.options.fps, &self.stop)
+    }
+}
+
+/// Shows the frames of `decoder` at `fps`, dropping frames whose time has
+/// already passed when they arrive.
+pub fn play_decoder(matrix: &mut Matrix, decoder: &mut Decoder, fps: f64, stop: &StopHandle) -> io::Result<PlaybackStats> {
+    let mut displayed = matrix.get_canvas();
+    let mut offscreen = matrix.create_offscreen_canvas();
+    let width = decoder.width();
+
+    let mut stats = PlaybackStats::default();
+    let mut pacer = None;
+    let mut index = 0;
+    while !stop.is_stopped() {
+        let frame = match decoder.next_frame()? {
+            Some(frame) => frame,
+            None => break,
+        };
+        // start the clock on the first frame, so decoder startup isn't counted as lag
+        let pacer = pacer.get_or_insert_with(|| Pacer::new(Instant::now(), fps));
+
+        match pacer.pace(index, Instant::now()) {
+            Pace::Drop => stats.dropped += 1,
+            Pace::Show(_) => {
+                draw_rgb24(&mut offscreen, width, frame);
+                while !stop.is_stopped() {
+                    match pacer.pace(index, Instant::now()) {
+                        Pace::Show(wait) if wait > Duration::from_millis(0) => sleep(wait.min(STOP_POLL_INTERVAL)),
+                        _ => break,
+                    }
+                }
+                matrix.swap_canvas_on_vsync(&mut offscreen, &mut displayed);
+                std::mem::swap(&mut offscreen, &mut displayed);
+                stats.shown += 1;
+            }
+        }
+        index += 1;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for ffmpeg with one frame per letter of `fills`, every
+    /// byte of the frame set to that letter.
+    fn synthetic_frames(width: i32, height: i32, fills: &str) -> Command {
+        let script = format!(
+            "for c in {}; do head -c {} /dev/zero | tr '\\0' $c; done",
+            fills.chars().map(String::from).collect::<Vec<_>>().join(" "),
+            width * height * 3
+        );
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn reads_frames_from_a_process() {
+        let mut decoder = Decoder::spawn(synthetic_frames(4, 2, "ABC"), 4, 2).unwrap();
+        for expected in b"ABC" {
+            let frame = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(frame.len(), 4 * 2 * 3);
+            assert!(frame.iter().all(|byte| byte == expected));
+        }
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("printf abcde");
+        let mut decoder = Decoder::spawn(command, 4, 2).unwrap();
+        assert_eq!(decoder.next_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn pacer_drops_late_frames() {
+        let start = Instant::now();
+        let pacer = Pacer::new(start, 10.0);
+        assert_eq!(pacer.pace(0, start), Pace::Show(Duration::from_millis(0)));
+        assert_eq!(pacer.pace(2, start + Duration::from_millis(150)), Pace::Show(Duration::from_millis(50)));
+        assert_eq!(pacer.pace(2, start + Duration::from_millis(300)), Pace::Drop);
+        assert_eq!(pacer.pace(2, start + Duration::from_millis(299)), Pace::Show(Duration::from_millis(0)));
+    }
+}